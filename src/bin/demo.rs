@@ -1,9 +1,13 @@
 use std::path::PathBuf;
 
-use locker_db::lsm_trees::client::LSMTreeClient;
+use locker_db::lsm_trees::{client::LSMTreeClient, options::LSMTreeOptions};
 use rocket::tokio;
 
 #[tokio::main]
 async fn main() {
-    let x = LSMTreeClient::<String>::new(PathBuf::from("./testing")).await;
+    let client =
+        LSMTreeClient::<String>::new(PathBuf::from("./testing"), LSMTreeOptions::default())
+            .await
+            .unwrap();
+    client.shutdown().await.unwrap();
 }
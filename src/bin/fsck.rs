@@ -0,0 +1,61 @@
+use std::{env, path::PathBuf, process::exit};
+
+use locker_db::lsm_trees::{fsck::check, options::LSMTreeOptions};
+use rocket::tokio;
+
+/// Checks a tree written by the server without changing it, reading
+/// `LOCKER_WAL_ARCHIVE` as the server does. Exits with 1 if the tree is
+/// damaged, or 2 if it could not be checked at all.
+#[tokio::main]
+async fn main() {
+    let args: Vec<_> = env::args().skip(1).collect();
+    let dir = match args.as_slice() {
+        [dir] => PathBuf::from(dir),
+        _ => {
+            eprintln!("Usage: locker-fsck <tree dir>");
+            exit(2);
+        }
+    };
+    let options = LSMTreeOptions {
+        wal_archive: env::var("LOCKER_WAL_ARCHIVE").ok().map(PathBuf::from),
+        ..LSMTreeOptions::default()
+    };
+    let report = match check::<String>(&dir, &options).await {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Check failed: {}", e);
+            exit(2);
+        }
+    };
+    println!(
+        "Checked {} tables holding {} entries, and {} WALs holding {} batches",
+        report.tables, report.entries, report.wals, report.batches
+    );
+    if report.orphan_batches > 0 {
+        println!(
+            "Orphan WALs hold {} batches that will not be replayed",
+            report.orphan_batches
+        );
+    }
+    for path in report.orphans.iter() {
+        println!(
+            "orphan: {} (deleted when the tree is opened)",
+            path.display()
+        );
+    }
+    for path in report.archived.iter() {
+        println!(
+            "orphan: {} (archived when the tree is opened)",
+            path.display()
+        );
+    }
+    for warning in report.warnings.iter() {
+        println!("warning: {}", warning);
+    }
+    for error in report.errors.iter() {
+        println!("error: {}", error);
+    }
+    if !report.errors.is_empty() {
+        exit(1);
+    }
+}
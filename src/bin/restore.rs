@@ -0,0 +1,42 @@
+use std::{
+    env,
+    path::PathBuf,
+    process::exit,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use locker_db::lsm_trees::restore::{restore, RecoveryTarget};
+use rocket::tokio;
+
+const USAGE: &str =
+    "Usage: restore <tree dir> <archive dir> (--sequence <n> | --time <unix seconds>)";
+
+/// Rolls a copy of a tree written by the server forward to a sequence number
+/// or time, using the WALs the server archived.
+#[tokio::main]
+async fn main() {
+    let args: Vec<_> = env::args().skip(1).collect();
+    let target = match args.get(2..4) {
+        Some([flag, value]) => match (flag.as_str(), value.parse::<u64>()) {
+            ("--sequence", Ok(x)) => Some(RecoveryTarget::Sequence(x)),
+            ("--time", Ok(x)) => Some(RecoveryTarget::Time(UNIX_EPOCH + Duration::from_secs(x))),
+            _ => None,
+        },
+        _ => None,
+    };
+    let target = match target {
+        Some(x) if args.len() == 4 => x,
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+    let (dir, archive) = (PathBuf::from(&args[0]), PathBuf::from(&args[1]));
+    match restore::<String>(&dir, &archive, target).await {
+        Ok(sequence) => println!("Restored {} to sequence {}", dir.display(), sequence),
+        Err(e) => {
+            eprintln!("Restore failed: {}", e);
+            exit(1);
+        }
+    }
+}
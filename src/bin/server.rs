@@ -1,49 +1,243 @@
+use std::{env, path::PathBuf, sync::Arc, time::Duration};
+
 use locker_db::{
     core::key::{Key, KEY_SIZE},
-    lsm_trees::client::LSMTreeClient,
+    lsm_trees::{client::LSMTreeClient, options::LSMTreeOptions, replication},
+    Error,
 };
-use log::LevelFilter;
+use log::{error, warn, LevelFilter};
 use pretty_env_logger::env_logger::Target;
-use rocket::{http::Status, State};
+use rocket::{
+    fairing::AdHoc,
+    futures::StreamExt,
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::stream::{Event, EventStream},
+    tokio::{net::TcpListener, spawn, time::sleep},
+    Request, State,
+};
 
 #[macro_use]
 extern crate rocket;
 
+/// Storage errors are reported as temporary, so clients retry rather than
+/// treating the key as missing.
+fn unavailable(e: Error) -> Status {
+    error!("Request failed: {}", e);
+    Status::ServiceUnavailable
+}
+
+/// Managed when the server follows a leader, whose writes it applies.
+struct Follower;
+
+/// Rejects writes with 403 when the server follows a leader, since they
+/// would diverge from it.
+struct Writable;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Writable {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        match request.rocket().state::<Follower>() {
+            Some(_) => Outcome::Failure((Status::Forbidden, ())),
+            None => Outcome::Success(Writable),
+        }
+    }
+}
+
 #[get("/get/<key>")]
-async fn get(key: &str, map: &State<LSMTreeClient<String>>) -> Result<String, Status> {
+async fn get(key: &str, map: &State<Arc<LSMTreeClient<String>>>) -> Result<String, Status> {
     let mut slice = [0u8; KEY_SIZE];
     hex::decode_to_slice(key, &mut slice).map_err(|_| Status::BadRequest)?;
-    map.read(&Key::Key(slice)).await.ok_or(Status::NotFound)
+    map.read(&Key::Key(slice))
+        .await
+        .map_err(unavailable)?
+        .ok_or(Status::NotFound)
 }
 
 #[post("/set/<key>", data = "<value>")]
-async fn set(key: String, value: String, map: &State<LSMTreeClient<String>>) -> Status {
+async fn set(
+    key: String,
+    value: String,
+    _writable: Writable,
+    map: &State<Arc<LSMTreeClient<String>>>,
+) -> Status {
     let mut slice = [0u8; KEY_SIZE];
     if hex::decode_to_slice(key, &mut slice).is_err() {
         return Status::BadRequest;
     }
-    map.write(Key::Key(slice), Some(value)).await;
-    Status::Ok
+    match map.write(Key::Key(slice), Some(value)).await {
+        Ok(()) => Status::Ok,
+        Err(e) => unavailable(e),
+    }
 }
 
 #[post("/delete/<key>")]
-async fn delete(key: String, map: &State<LSMTreeClient<String>>) -> Status {
+async fn delete(
+    key: String,
+    _writable: Writable,
+    map: &State<Arc<LSMTreeClient<String>>>,
+) -> Status {
     let mut slice = [0u8; KEY_SIZE];
     if hex::decode_to_slice(key, &mut slice).is_err() {
         return Status::BadRequest;
     }
-    map.write(Key::Key(slice), None).await;
-    Status::Ok
+    match map.write(Key::Key(slice), None).await {
+        Ok(()) => Status::Ok,
+        Err(e) => unavailable(e),
+    }
 }
 
+/// Sets the key to the body only if it currently holds `expected`, or is
+/// absent if no value is expected. Responds with 409 if it does not.
+#[post("/cas/<key>?<expected>", data = "<value>")]
+async fn cas(
+    key: String,
+    expected: Option<String>,
+    value: String,
+    _writable: Writable,
+    map: &State<Arc<LSMTreeClient<String>>>,
+) -> Status {
+    let mut slice = [0u8; KEY_SIZE];
+    if hex::decode_to_slice(key, &mut slice).is_err() {
+        return Status::BadRequest;
+    }
+    match map
+        .compare_and_set(Key::Key(slice), expected, Some(value))
+        .await
+    {
+        Ok(true) => Status::Ok,
+        Ok(false) => Status::Conflict,
+        Err(e) => unavailable(e),
+    }
+}
+
+#[post("/put_if_absent/<key>", data = "<value>")]
+async fn put_if_absent(
+    key: String,
+    value: String,
+    _writable: Writable,
+    map: &State<Arc<LSMTreeClient<String>>>,
+) -> Status {
+    let mut slice = [0u8; KEY_SIZE];
+    if hex::decode_to_slice(key, &mut slice).is_err() {
+        return Status::BadRequest;
+    }
+    match map.put_if_absent(Key::Key(slice), value).await {
+        Ok(true) => Status::Ok,
+        Ok(false) => Status::Conflict,
+        Err(e) => unavailable(e),
+    }
+}
+
+/// The `Last-Event-ID` header sent by event stream clients when they
+/// reconnect.
+struct LastEventId(Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let id = request.headers().get_one("Last-Event-ID");
+        Outcome::Success(LastEventId(id.and_then(|x| x.parse().ok())))
+    }
+}
+
+/// Streams each write committed after sequence `from` (or after the
+/// `Last-Event-ID` of a reconnecting client) as a `set` or `delete` event,
+/// whose id is the write's sequence number. The data holds the key, followed
+/// by the value on the next line for `set` events. The stream ends with an
+/// `error` event if the client falls behind. Responds with 410 if the writes
+/// are no longer retained.
+#[get("/subscribe?<from>")]
+async fn subscribe(
+    from: Option<u64>,
+    last: LastEventId,
+    map: &State<Arc<LSMTreeClient<String>>>,
+) -> Result<EventStream![Event + '_], Status> {
+    let from = last.0.or(from).unwrap_or(0);
+    let mut changes = match map.subscribe(from).await {
+        Ok(x) => Box::pin(x),
+        Err(Error::InvalidArgument(_)) => return Err(Status::Gone),
+        Err(e) => return Err(unavailable(e)),
+    };
+    Ok(EventStream! {
+        while let Some(change) = changes.next().await {
+            let entry = match change {
+                Ok(x) => x,
+                Err(e) => {
+                    yield Event::data(e.to_string()).event("error");
+                    break;
+                }
+            };
+            let key = entry.key.hex();
+            let event = match entry.data.into_data() {
+                Some(value) => Event::data(format!("{}\n{}", key, value)).event("set"),
+                None => Event::data(key).event("delete"),
+            };
+            yield event.id(entry.sequence.to_string());
+        }
+    })
+}
+
+/// The tree is stored in `LOCKER_DIR`, and the WALs of flushed buffers are
+/// kept in `LOCKER_WAL_ARCHIVE` if it is set, for use by `restore`. If
+/// `LOCKER_REPLICATION_ADDR` is set, followers may connect to that address to
+/// receive its writes. If `LOCKER_LEADER` is set, the server instead follows
+/// the leader at that address and rejects writes of its own.
 #[launch]
 async fn rocket() -> _ {
     pretty_env_logger::formatted_builder()
         .target(Target::Stdout)
         .filter_level(LevelFilter::Debug)
         .init();
-    let map = LSMTreeClient::<String>::new("./testing".into()).await;
-    rocket::build()
-        .manage(map)
-        .mount("/", routes![get, set, delete])
+    let dir = env::var("LOCKER_DIR").unwrap_or_else(|_| "./testing".to_string());
+    let options = LSMTreeOptions {
+        wal_archive: env::var("LOCKER_WAL_ARCHIVE").ok().map(PathBuf::from),
+        ..LSMTreeOptions::default()
+    };
+    let map = Arc::new(
+        LSMTreeClient::<String>::new(dir.into(), options)
+            .await
+            .unwrap(),
+    );
+    let mut rocket = rocket::build().manage(map.clone());
+    if let Ok(addr) = env::var("LOCKER_REPLICATION_ADDR") {
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let map = map.clone();
+        spawn(async move {
+            if let Err(e) = replication::serve(map, listener).await {
+                error!("Stopped serving followers: {}", e);
+            }
+        });
+    }
+    if let Ok(leader) = env::var("LOCKER_LEADER") {
+        rocket = rocket.manage(Follower);
+        spawn(async move {
+            loop {
+                match replication::follow(&map, &leader).await {
+                    Ok(()) => warn!("Leader {} closed the connection", leader),
+                    Err(Error::Closed) => break,
+                    Err(e) => warn!("Lost leader {}: {}", leader, e),
+                }
+                sleep(Duration::from_secs(1)).await;
+            }
+        });
+    }
+    rocket
+        .attach(AdHoc::on_shutdown("Shutdown tree", |rocket| {
+            Box::pin(async move {
+                if let Some(map) = rocket.state::<Arc<LSMTreeClient<String>>>() {
+                    if let Err(e) = map.shutdown().await {
+                        error!("Failed to shut down tree: {}", e);
+                    }
+                }
+            })
+        }))
+        .mount(
+            "/",
+            routes![get, set, delete, cas, put_if_absent, subscribe],
+        )
 }
@@ -1,3 +1,5 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use rocket::serde::{Deserialize, Serialize};
 
 use super::key::Key;
@@ -7,33 +9,71 @@ use super::key::Key;
 pub enum EntryData<T> {
     Data(T),
     Deleted,
+    /// Data that is treated as deleted once the time, in milliseconds since
+    /// the Unix epoch, has passed.
+    Expiring(T, u64),
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
 }
 
 impl<T> EntryData<T> {
+    /// Returns data that expires once the duration has passed.
+    pub fn expiring(value: T, ttl: Duration) -> EntryData<T> {
+        EntryData::Expiring(value, now().saturating_add(ttl.as_millis() as u64))
+    }
+
+    /// Returns the value, or `None` if it was deleted or has expired.
     pub fn into_data(self) -> Option<T> {
+        self.into_data_at(now())
+    }
+
+    /// Returns the value, or `None` if it was deleted or had expired by the
+    /// time, in milliseconds since the Unix epoch.
+    pub fn into_data_at(self, time: u64) -> Option<T> {
         match self {
             EntryData::Data(x) => Some(x),
-            EntryData::Deleted => None,
+            EntryData::Expiring(x, expiry) if expiry > time => Some(x),
+            _ => None,
         }
     }
 
     pub fn data(&self) -> Option<&T> {
         match self {
             EntryData::Data(x) => Some(x),
-            EntryData::Deleted => None,
+            EntryData::Expiring(x, expiry) if *expiry > now() => Some(x),
+            _ => None,
         }
     }
+
+    /// Returns true if the entry hides any older value of the key without
+    /// holding one of its own.
+    pub fn is_deleted(&self) -> bool {
+        self.data().is_none()
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(crate = "rocket::serde")]
 pub struct Entry<T> {
     pub key: Key,
     pub data: EntryData<T>,
+    /// Orders the writes to a key. Assigned by the write buffer when the
+    /// entry is logged; entries written by older versions have sequence 0.
+    pub sequence: u64,
 }
 
 impl<T> Entry<T> {
     pub fn new(key: Key, data: EntryData<T>) -> Entry<T> {
-        Entry { key, data }
+        Entry {
+            key,
+            data,
+            sequence: 0,
+        }
     }
 }
@@ -4,10 +4,13 @@ use rocket::serde::{Deserialize, Serialize};
 
 pub const KEY_SIZE: usize = 16;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+/// Keys of different variants never compare equal, and every fixed-size key
+/// sorts before every variable-length key.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 #[serde(crate = "rocket::serde")]
 pub enum Key {
     Key([u8; KEY_SIZE]),
+    Bytes(Vec<u8>),
 }
 
 impl Key {
@@ -18,9 +21,10 @@ impl Key {
         Key::Key(bytes)
     }
 
-    pub fn bytes(&self) -> &[u8; KEY_SIZE] {
+    pub fn bytes(&self) -> &[u8] {
         match self {
             Key::Key(b) => b,
+            Key::Bytes(b) => b,
         }
     }
 
@@ -29,10 +33,26 @@ impl Key {
     }
 }
 
-impl AsRef<[u8; KEY_SIZE]> for Key {
-    fn as_ref(&self) -> &[u8; KEY_SIZE] {
-        match self {
-            Key::Key(x) => x,
-        }
+impl AsRef<[u8]> for Key {
+    fn as_ref(&self) -> &[u8] {
+        self.bytes()
+    }
+}
+
+impl From<Vec<u8>> for Key {
+    fn from(bytes: Vec<u8>) -> Key {
+        Key::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Key {
+    fn from(bytes: &[u8]) -> Key {
+        Key::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<&str> for Key {
+    fn from(s: &str) -> Key {
+        Key::Bytes(s.as_bytes().to_vec())
     }
 }
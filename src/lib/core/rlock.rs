@@ -10,7 +10,7 @@ impl<T> RLock<T> {
         RLock { lock }
     }
 
-    pub async fn read(&self) -> RwLockReadGuard<T> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
         self.lock.read()
     }
 }
@@ -0,0 +1,95 @@
+use std::{
+    fmt::{self, Display},
+    io,
+    sync::Arc,
+};
+
+use crate::persistance::wal::WALError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors returned by the storage engine. Errors can be cloned, so that a
+/// failed group commit can report the same error to every writer in it.
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// Reading or writing a file failed.
+    Io(Arc<io::Error>),
+    /// A file on disk does not hold what it should.
+    Corruption(String),
+    /// A value could not be serialized or deserialized.
+    Serialization(Arc<bincode::Error>),
+    /// The options passed when opening a tree are invalid, or do not match
+    /// the existing tree.
+    InvalidOptions(String),
+    /// An argument is invalid, such as keys written to a table out of order.
+    InvalidArgument(String),
+    /// The tree has been shut down, or its background service has failed.
+    Closed,
+    /// A transaction read a key that was changed before it could commit.
+    Conflict,
+    /// A subscriber fell too far behind the writes to the tree, and must
+    /// subscribe again from the last sequence it saw.
+    Lagged,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Corruption(message) => write!(f, "Corruption: {}", message),
+            Error::Serialization(e) => write!(f, "Serialization error: {}", e),
+            Error::InvalidOptions(message) => write!(f, "Invalid options: {}", message),
+            Error::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            Error::Closed => write!(f, "The tree has been shut down"),
+            Error::Conflict => write!(f, "The transaction conflicts with another write"),
+            Error::Lagged => write!(f, "The subscriber fell too far behind"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e.as_ref()),
+            Error::Serialization(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(Arc::new(e))
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Serialization(Arc::new(e))
+    }
+}
+
+impl From<WALError> for Error {
+    fn from(e: WALError) -> Self {
+        Error::Corruption(e.to_string())
+    }
+}
+
+/// The file and WAL helpers report errors through `anyhow`. Anything that is
+/// not an I/O or serialization error is a malformed file.
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<io::Error>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<bincode::Error>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        match e.downcast::<WALError>() {
+            Ok(e) => e.into(),
+            Err(e) => Error::Corruption(e.to_string()),
+        }
+    }
+}
@@ -1,79 +1,1188 @@
-use std::{fmt::Debug, path::PathBuf, sync::Arc};
+use std::{
+    fmt::Debug,
+    future::Future,
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use log::{debug, info};
+use log::{error, info};
+use parking_lot::Mutex;
 use rocket::{
+    futures::{stream, Stream, StreamExt},
     serde::{DeserializeOwned, Serialize},
-    tokio::{fs::metadata, spawn},
+    tokio::{
+        fs::{metadata, remove_file},
+        spawn,
+        task::JoinHandle,
+    },
 };
 
-use crate::core::{
-    entry::{Entry, EntryData},
-    key::Key,
+use crate::{
+    core::{
+        entry::{Entry, EntryData},
+        key::Key,
+    },
+    sstables::{sstable::SSTable, write_buffer::Durability},
+    Error, Result,
 };
 
-use super::{lsm_tree::LSMTree, service::LSMTreeService};
+use super::{
+    lsm_tree::{link_or_copy, LSMTree},
+    options::LSMTreeOptions,
+    service::{sync_buffer, LSMTreeService},
+    snapshot::Snapshot,
+    transaction::Transaction,
+};
 
 pub struct LSMTreeClient<T: Serialize + DeserializeOwned> {
-    tree: Arc<LSMTree<T>>,
+    pub(super) tree: Arc<LSMTree<T>>,
+    service: Mutex<Option<JoinHandle<Result<()>>>>,
 }
 
 impl<T: Serialize + DeserializeOwned + Clone + Send + Sync + Debug + 'static> LSMTreeClient<T> {
-    pub async fn new(dir: PathBuf) -> LSMTreeClient<T> {
+    pub async fn new(dir: PathBuf, options: LSMTreeOptions) -> Result<LSMTreeClient<T>> {
         let tree = if metadata(&dir).await.is_ok() {
-            LSMTree::load(dir).await
+            LSMTree::load(dir, options).await?
         } else {
-            LSMTree::new(dir).await
+            LSMTree::new(dir, options).await?
         };
         let tree = Arc::new(tree);
-        let t = LSMTreeClient { tree: tree.clone() };
-        let service = LSMTreeService::new(tree);
-        spawn(service.run());
-        t
-    }
-
-    pub async fn write(&self, key: Key, data: Option<T>) {
-        info!("Setting {} to {:?}", key.hex(), data);
-        self.tree
-            .buffers
-            .read()
-            .await
-            .buffer
-            .write(Entry::new(
-                key,
-                data.map(EntryData::Data).unwrap_or(EntryData::Deleted),
-            ))
+        spawn(sync_buffer(Arc::downgrade(&tree)));
+        let service = LSMTreeService::new(tree.clone());
+        Ok(LSMTreeClient {
+            tree,
+            service: Mutex::new(Some(spawn(service.run()))),
+        })
+    }
+
+    /// Stops accepting writes, and waits for the background service to
+    /// finish its current work, save the state of the tree and exit. Writes
+    /// made afterwards fail with `Error::Closed`. If the service had already
+    /// stopped because of an error, that error is returned, or
+    /// `Error::Closed` if it panicked.
+    pub async fn shutdown(&self) -> Result<()> {
+        let service = match self.service.lock().take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        info!("Shutting down tree in {:?}", self.tree.dir);
+        self.tree.close().await;
+        service.await.unwrap_or_else(|e| {
+            error!("Background service panicked: {}", e);
+            Err(Error::Closed)
+        })
+    }
+
+    pub async fn write(&self, key: Key, data: Option<T>) -> Result<()> {
+        self.write_with_durability(key, data, self.tree.options.durability)
+            .await
+    }
+
+    /// Writes the key with the given durability instead of the default one
+    /// from the tree options.
+    pub async fn write_with_durability(
+        &self,
+        key: Key,
+        data: Option<T>,
+        durability: Durability,
+    ) -> Result<()> {
+        info!("Setting {} to {:?} ({:?})", key.hex(), data, durability);
+        self.write_entry(
+            Entry::new(key, data.map(EntryData::Data).unwrap_or(EntryData::Deleted)),
+            durability,
+        )
+        .await
+    }
+
+    /// Sets the key to a value that reads as absent once the duration has
+    /// passed. Expired values are removed when their tables are compacted.
+    pub async fn write_with_ttl(&self, key: Key, value: T, ttl: Duration) -> Result<()> {
+        info!("Setting {} to {:?} for {:?}", key.hex(), value, ttl);
+        self.write_entry(
+            Entry::new(key, EntryData::expiring(value, ttl)),
+            self.tree.options.durability,
+        )
+        .await
+    }
+
+    async fn write_entry(&self, entry: Entry<T>, durability: Durability) -> Result<()> {
+        self.tree.throttle().await?;
+        let lock = self.tree.buffers.read().await;
+        self.tree.check_writable()?;
+        lock.buffer.write(entry, durability).await
+    }
+
+    /// Applies a batch of writes replicated from another tree, keeping the
+    /// sequence number it was given there.
+    pub(super) async fn apply(&self, batch: Vec<Entry<T>>) -> Result<()> {
+        self.tree.throttle().await?;
+        let lock = self.tree.buffers.read().await;
+        if self.tree.is_closed() {
+            return Err(Error::Closed);
+        }
+        lock.buffer
+            .write_replicated(batch, self.tree.options.durability)
+            .await
+    }
+
+    /// Applies all of the writes atomically, so that a crash cannot leave only
+    /// some of them persisted.
+    pub async fn write_batch(&self, batch: Vec<(Key, Option<T>)>) -> Result<()> {
+        info!("Applying batch of {} writes", batch.len());
+        let entries = batch
+            .into_iter()
+            .map(|(key, data)| {
+                Entry::new(key, data.map(EntryData::Data).unwrap_or(EntryData::Deleted))
+            })
+            .collect();
+        self.tree.throttle().await?;
+        let lock = self.tree.buffers.read().await;
+        self.tree.check_writable()?;
+        lock.buffer
+            .write_batch(entries, self.tree.options.durability)
+            .await
+    }
+
+    /// Writes `new` only if the key currently holds `expected`, where `None`
+    /// means the key is absent. Returns whether the write was made.
+    pub async fn compare_and_set(
+        &self,
+        key: Key,
+        expected: Option<T>,
+        new: Option<T>,
+    ) -> Result<bool>
+    where
+        T: PartialEq,
+    {
+        info!("Setting {} to {:?} if it is {:?}", key.hex(), new, expected);
+        self.tree.throttle().await?;
+        let lock = self.tree.buffers.read().await;
+        self.tree.check_writable()?;
+        let entry = Entry::new(
+            key.clone(),
+            new.map(EntryData::Data).unwrap_or(EntryData::Deleted),
+        );
+        let condition = || async {
+            let current = self.tree.snapshot_of(&lock).read(&key).await?;
+            Ok(current == expected)
+        };
+        lock.buffer
+            .write_batch_if(vec![entry], self.tree.options.durability, condition)
+            .await
+    }
+
+    /// Writes the value only if the key is absent. Returns whether the write
+    /// was made.
+    pub async fn put_if_absent(&self, key: Key, value: T) -> Result<bool>
+    where
+        T: PartialEq,
+    {
+        self.compare_and_set(key, None, Some(value)).await
+    }
+
+    /// Runs `f` in a transaction, then commits its writes atomically. Fails
+    /// with `Error::Conflict`, writing nothing, if any key it read was changed
+    /// in the meantime, in which case the transaction may be retried.
+    pub async fn transaction<F, Fut, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(Transaction<T>) -> Fut,
+        Fut: Future<Output = Result<R>>,
+    {
+        let tx = Transaction::new(self.tree.clone(), self.snapshot().await);
+        let result = f(tx.clone()).await?;
+        tx.commit().await?;
+        Ok(result)
+    }
+
+    /// Adds a table written by `SSTableWriter` to the tree, replacing the
+    /// values of any keys it holds as though they had all been written at
+    /// once, with a single new sequence number. The file is hard linked into
+    /// the tree's directory where possible and copied otherwise, so it must
+    /// not be modified afterwards, but may be removed. Every entry of the
+    /// table is checked before it is added. Tables are not sent to followers,
+    /// so this fails with `Error::InvalidArgument` once the tree is served,
+    /// as it does once the tree follows a leader.
+    pub async fn ingest_sstable(&self, path: &Path) -> Result<()> {
+        info!("Ingesting {}", path.display());
+        match self.link_sstable(path).await? {
+            Some(table) => self.tree.ingest(table, false).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Adds a table sent by a leader, keeping the sequences it was given
+    /// there: that of every entry, or `sequence` if the leader ingested it.
+    pub(super) async fn install_sstable(&self, path: &Path, sequence: Option<u64>) -> Result<()> {
+        match self.link_sstable(path).await? {
+            Some(mut table) => {
+                if let Some(sequence) = sequence {
+                    table.set_sequence(sequence);
+                }
+                self.tree.ingest(table, true).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Links the table into the tables directory and checks every entry of
+    /// it. Returns `None`, leaving nothing behind, if the table is empty.
+    async fn link_sstable(&self, path: &Path) -> Result<Option<SSTable<T>>> {
+        let (dir, id) = (self.tree.tables_dir(), Key::new().hex());
+        let target = dir.join(&id).with_extension("sst");
+        link_or_copy(path, &target).await?;
+        let table = match SSTable::new(&dir, id).await {
+            Ok(x) => x,
+            Err(e) => {
+                remove_file(&target).await?;
+                return Err(e);
+            }
+        };
+        if let Err(e) = table.verify().await {
+            table.delete().await?;
+            return Err(e);
+        }
+        if table.len() == 0 {
+            table.delete().await?;
+            return Ok(None);
+        }
+        Ok(Some(table))
+    }
+
+    /// Writes a consistent copy of the tree to `dest_dir`, which must not
+    /// exist, while writes continue. The copy can be opened with `new`,
+    /// and shares the files of its tables with this tree where the file
+    /// system allows it.
+    pub async fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        info!("Checkpointing to {}", dest_dir.display());
+        self.tree.checkpoint(dest_dir).await
+    }
+
+    pub async fn read(&self, key: &Key) -> Result<Option<T>> {
+        self.snapshot().await.read(key).await
+    }
+
+    /// Returns the value of the key along with the sequence number of the
+    /// write that set it.
+    pub async fn read_with_version(&self, key: &Key) -> Result<Option<(u64, T)>> {
+        self.snapshot().await.read_with_version(key).await
+    }
+
+    /// Returns the live entries whose keys lie within the range, in key order.
+    /// The stream ends after the first error.
+    pub async fn scan(&self, range: impl RangeBounds<Key>) -> impl Stream<Item = Result<(Key, T)>> {
+        self.snapshot().await.scan(range)
+    }
+
+    /// Returns the writes committed after `from_sequence`, followed by each
+    /// write as it is committed, in sequence order. Writes still held in a
+    /// WAL are replayed, so a subscriber can resume from the last sequence it
+    /// saw; older writes have been merged into tables, and asking for them
+    /// fails with `Error::InvalidArgument`. The stream ends with
+    /// `Error::Lagged` if the subscriber falls more than
+    /// `subscriber_capacity` writes behind. Ingested tables are not reported,
+    /// nor are writes made with `Durability::None`.
+    pub async fn subscribe(
+        &self,
+        from_sequence: u64,
+    ) -> Result<impl Stream<Item = Result<Entry<T>>>> {
+        let lock = self.tree.buffers.read().await;
+        let batches = self.tree.changes_of(&lock, from_sequence)?;
+        Ok(batches.flat_map(|batch| {
+            let entries: Vec<_> = match batch {
+                Ok(x) => x.iter().cloned().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            };
+            stream::iter(entries)
+        }))
+    }
+
+    /// Returns a view of the tree as it is now, so that a series of reads and
+    /// scans see a single consistent version. Holding a snapshot keeps the
+    /// tables it refers to on disk.
+    pub async fn snapshot(&self) -> Snapshot<T> {
+        self.tree.snapshot().await
+    }
+}
+
+#[cfg(test)]
+impl<T: Serialize + DeserializeOwned + Clone> LSMTreeClient<T> {
+    /// Waits until the tree has tables, and every queued builder has been
+    /// written to one.
+    pub(super) async fn wait_for_flush(&self) {
+        for _ in 0..500 {
+            let state = self.tree.state().await;
+            if !state.runs.is_empty() && state.builders.is_empty() {
+                return;
+            }
+            rocket::tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Queued builders were not flushed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use rocket::{
+        futures::StreamExt,
+        tokio::{
+            self,
+            fs::{create_dir, metadata, remove_dir_all, remove_file, write},
+            spawn,
+            time::{sleep, timeout},
+        },
+    };
+
+    use crate::{
+        core::key::Key,
+        lsm_trees::options::{CompactionStrategy, LSMTreeOptions, LeveledOptions},
+        sstables::{block::Compression, sstable_writer::SSTableWriter, write_buffer::Durability},
+        testing::{options, TempDir},
+        Error,
+    };
+
+    use super::LSMTreeClient;
+
+    #[tokio::test]
+    async fn test_flush() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        let keys: Vec<_> = (0..100)
+            .map(|i| Key::from(format!("key{:03}", i).as_str()))
+            .collect();
+        for (i, k) in keys.iter().enumerate() {
+            client
+                .write(k.clone(), Some(format!("value{}", i)))
+                .await
+                .unwrap();
+        }
+        for k in keys.iter().step_by(3) {
+            client.write(k.clone(), None).await.unwrap();
+        }
+        client.wait_for_flush().await;
+        assert!(client.tree.first.load().is_some());
+
+        for (i, k) in keys.iter().enumerate() {
+            let expected = (i % 3 != 0).then(|| format!("value{}", i));
+            assert_eq!(client.read(k).await.unwrap(), expected);
+        }
+        let scanned: Vec<_> = client
+            .scan(keys[10].clone()..keys[20].clone())
+            .await
+            .map(|x| x.unwrap())
+            .collect()
             .await;
+        let expected: Vec<_> = (10..20)
+            .filter(|i| i % 3 != 0)
+            .map(|i| (keys[i].clone(), format!("value{}", i)))
+            .collect();
+        assert_eq!(scanned, expected);
+
+        client.shutdown().await.unwrap();
     }
 
-    pub async fn read(&self, key: &Key) -> Option<T> {
-        {
-            let lock = self.tree.buffers.read().await;
-            if let Some(x) = lock.buffer.read(key) {
-                debug!("Found {} in main buffer {}.", key.hex(), lock.buffer.id());
-                return x.into_data();
+    #[tokio::test]
+    async fn test_leveled() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let options = LSMTreeOptions {
+            compaction: CompactionStrategy::Leveled(LeveledOptions {
+                level0_tables: 2,
+                level1_size: 1024,
+                level_multiplier: 2,
+                table_size: 512,
+            }),
+            ..options()
+        };
+        let client = LSMTreeClient::<String>::new(dir.clone(), options.clone())
+            .await
+            .unwrap();
+        let keys: Vec<_> = (0..200)
+            .map(|i| Key::from(format!("key{:03}", (i * 7) % 200).as_str()))
+            .collect();
+        for (i, k) in keys.iter().enumerate() {
+            client
+                .write(k.clone(), Some(format!("value{}", i)))
+                .await
+                .unwrap();
+        }
+        // Wait for compaction to push tables past the first level.
+        let mut compacted = false;
+        for _ in 0..500 {
+            let state = client.tree.state().await;
+            if state.runs.iter().any(|r| r.level > 1) {
+                compacted = true;
+                break;
             }
-            for b in lock.builders.iter() {
-                if let Some(x) = b.read(key) {
-                    debug!("Found {} in builder {}.", key.hex(), b.id());
-                    return x.data().cloned();
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(compacted);
+        client.shutdown().await.unwrap();
+        drop(client);
+
+        let client = LSMTreeClient::<String>::new(dir.clone(), options)
+            .await
+            .unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(client.read(k).await.unwrap(), Some(format!("value{}", i)));
+        }
+        let scanned: Vec<_> = client.scan(..).await.map(|x| x.unwrap().0).collect().await;
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(scanned, expected);
+
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        let keys: Vec<_> = (0..50)
+            .map(|i| Key::from(format!("key{:03}", i).as_str()))
+            .collect();
+        for k in keys.iter() {
+            client
+                .write(k.clone(), Some("old".to_string()))
+                .await
+                .unwrap();
+        }
+        let snapshot = client.snapshot().await;
+        for k in keys.iter().step_by(2) {
+            client.write(k.clone(), None).await.unwrap();
+        }
+        for k in keys.iter().skip(1).step_by(2) {
+            client
+                .write(k.clone(), Some("new".to_string()))
+                .await
+                .unwrap();
+        }
+        client
+            .write(Key::from("extra"), Some("new".to_string()))
+            .await
+            .unwrap();
+        client.wait_for_flush().await;
+
+        for k in keys.iter() {
+            assert_eq!(snapshot.read(k).await.unwrap(), Some("old".to_string()));
+        }
+        assert_eq!(snapshot.read(&Key::from("extra")).await.unwrap(), None);
+        let scanned: Vec<_> = snapshot.scan(..).map(|x| x.unwrap()).collect().await;
+        let expected: Vec<_> = keys
+            .iter()
+            .map(|k| (k.clone(), "old".to_string()))
+            .collect();
+        assert_eq!(scanned, expected);
+
+        assert_eq!(client.read(&keys[0]).await.unwrap(), None);
+        assert_eq!(
+            client.read(&keys[1]).await.unwrap(),
+            Some("new".to_string())
+        );
+        assert!(client.snapshot().await.sequence() > snapshot.sequence());
+
+        drop(snapshot);
+        client.shutdown().await.unwrap();
+    }
+
+    async fn check_versions(client: &LSMTreeClient<String>, keys: &[Key]) {
+        let snapshot = client.snapshot().await;
+        assert_eq!(
+            snapshot.read_with_version(&keys[0]).await.unwrap(),
+            Some((51, "new".to_string()))
+        );
+        assert_eq!(snapshot.read_with_version(&keys[1]).await.unwrap(), None);
+        assert_eq!(
+            snapshot.read_with_version(&keys[2]).await.unwrap(),
+            Some((3, "old".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_versions() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        let keys: Vec<_> = (0..50)
+            .map(|i| Key::from(format!("key{:03}", i).as_str()))
+            .collect();
+        for k in keys.iter() {
+            client
+                .write(k.clone(), Some("old".to_string()))
+                .await
+                .unwrap();
+        }
+        client
+            .write(keys[0].clone(), Some("new".to_string()))
+            .await
+            .unwrap();
+        client.write(keys[1].clone(), None).await.unwrap();
+        client.wait_for_flush().await;
+
+        check_versions(&client, &keys).await;
+        client.shutdown().await.unwrap();
+        drop(client);
+
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        check_versions(&client, &keys).await;
+        client
+            .write(keys[2].clone(), Some("newer".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.read_with_version(&keys[2]).await.unwrap(),
+            Some((53, "newer".to_string()))
+        );
+
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compare_and_set() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let client = Arc::new(
+            LSMTreeClient::<String>::new(dir.clone(), options())
+                .await
+                .unwrap(),
+        );
+        let key = Key::from("counter");
+        assert!(client
+            .put_if_absent(key.clone(), "0".to_string())
+            .await
+            .unwrap());
+        assert!(!client
+            .put_if_absent(key.clone(), "1".to_string())
+            .await
+            .unwrap());
+
+        // Concurrent increments only succeed from the value they read.
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let (client, key) = (client.clone(), key.clone());
+                spawn(async move {
+                    for _ in 0..25 {
+                        loop {
+                            let current = client.read(&key).await.unwrap().unwrap();
+                            let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                            if client
+                                .compare_and_set(key.clone(), Some(current), Some(next))
+                                .await
+                                .unwrap()
+                            {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap();
+        }
+        assert_eq!(client.read(&key).await.unwrap(), Some("100".to_string()));
+
+        assert!(!client
+            .compare_and_set(key.clone(), Some("0".to_string()), None)
+            .await
+            .unwrap());
+        assert!(client
+            .compare_and_set(key.clone(), Some("100".to_string()), None)
+            .await
+            .unwrap());
+        assert_eq!(client.read(&key).await.unwrap(), None);
+
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let client = Arc::new(
+            LSMTreeClient::<String>::new(dir.clone(), options())
+                .await
+                .unwrap(),
+        );
+        let accounts: Vec<_> = (0..4)
+            .map(|i| Key::from(format!("account{}", i).as_str()))
+            .collect();
+        for k in accounts.iter() {
+            client
+                .write(k.clone(), Some("100".to_string()))
+                .await
+                .unwrap();
+        }
+
+        // Concurrent transfers never create or destroy money.
+        let tasks: Vec<_> = (0..4)
+            .map(|t| {
+                let (client, accounts) = (client.clone(), accounts.clone());
+                spawn(async move {
+                    for i in 0..20 {
+                        let from = accounts[(t + i) % 4].clone();
+                        let to = accounts[(t + i + 1) % 4].clone();
+                        loop {
+                            let (from, to) = (from.clone(), to.clone());
+                            let result = client
+                                .transaction(|tx| async move {
+                                    let a: u32 = tx.get(&from).await?.unwrap().parse().unwrap();
+                                    let b: u32 = tx.get(&to).await?.unwrap().parse().unwrap();
+                                    tx.put(from, (a - 1).to_string());
+                                    tx.put(to, (b + 1).to_string());
+                                    Ok(())
+                                })
+                                .await;
+                            match result {
+                                Ok(()) => break,
+                                Err(Error::Conflict) => continue,
+                                Err(e) => panic!("{}", e),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap();
+        }
+        let mut total = 0;
+        for k in accounts.iter() {
+            total += client
+                .read(k)
+                .await
+                .unwrap()
+                .unwrap()
+                .parse::<u32>()
+                .unwrap();
+        }
+        assert_eq!(total, 400);
+
+        // A write made after the transaction read a key aborts it.
+        let result = client
+            .transaction(|tx| {
+                let client = client.clone();
+                let accounts = accounts.clone();
+                async move {
+                    tx.get(&accounts[0]).await?;
+                    client.write(accounts[0].clone(), None).await?;
+                    tx.delete(accounts[1].clone());
+                    assert_eq!(tx.get(&accounts[1]).await?, None);
+                    Ok(())
                 }
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Conflict)));
+        assert!(client.read(&accounts[1]).await.unwrap().is_some());
+
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        let keys: Vec<_> = (0..50)
+            .map(|i| Key::from(format!("key{:03}", i).as_str()))
+            .collect();
+        for (i, k) in keys.iter().enumerate() {
+            client
+                .write(k.clone(), Some("old".to_string()))
+                .await
+                .unwrap();
+            // A zero TTL expires as soon as it is written.
+            let ttl = match i % 2 {
+                0 => Duration::ZERO,
+                _ => Duration::from_secs(3600),
+            };
+            client
+                .write_with_ttl(k.clone(), format!("value{}", i), ttl)
+                .await
+                .unwrap();
+        }
+        client.wait_for_flush().await;
+
+        // Expired values hide the older values of their keys.
+        for (i, k) in keys.iter().enumerate() {
+            let expected = (i % 2 == 1).then(|| format!("value{}", i));
+            assert_eq!(client.read(k).await.unwrap(), expected);
+        }
+        let scanned: Vec<_> = client.scan(..).await.map(|x| x.unwrap().0).collect().await;
+        let expected: Vec<_> = keys.iter().skip(1).step_by(2).cloned().collect();
+        assert_eq!(scanned, expected);
+
+        // A snapshot keeps seeing values that expire after it was taken.
+        let key = Key::from("expiring");
+        client
+            .write_with_ttl(key.clone(), "value".to_string(), Duration::from_millis(500))
+            .await
+            .unwrap();
+        let snapshot = client.snapshot().await;
+        let before = snapshot.read(&key).await.unwrap();
+        let scanned_before: Vec<_> = snapshot.scan(..).map(|x| x.unwrap().0).collect().await;
+        sleep(Duration::from_millis(600)).await;
+        assert_eq!(client.read(&key).await.unwrap(), None);
+        assert_eq!(snapshot.read(&key).await.unwrap(), before);
+        let scanned: Vec<_> = snapshot.scan(..).map(|x| x.unwrap().0).collect().await;
+        assert_eq!(scanned, scanned_before);
+        drop(snapshot);
+
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        client
+            .write(Key::from("a"), Some("1".to_string()))
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        client.shutdown().await.unwrap();
+        assert!(matches!(
+            client.write(Key::from("b"), None).await,
+            Err(Error::Closed)
+        ));
+        assert_eq!(
+            client.read(&Key::from("a")).await.unwrap(),
+            Some("1".to_string())
+        );
+        drop(client);
+
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.read(&Key::from("a")).await.unwrap(),
+            Some("1".to_string())
+        );
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_durability() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        let (k1, k2, k3) = (Key::from("a"), Key::from("b"), Key::from("c"));
+        client
+            .write_with_durability(k1.clone(), Some("1".to_string()), Durability::Sync)
+            .await
+            .unwrap();
+        client
+            .write_with_durability(k2.clone(), Some("2".to_string()), Durability::Buffered)
+            .await
+            .unwrap();
+        client
+            .write_with_durability(k3.clone(), Some("3".to_string()), Durability::None)
+            .await
+            .unwrap();
+        assert_eq!(client.read(&k3).await.unwrap(), Some("3".to_string()));
+        client.shutdown().await.unwrap();
+        drop(client);
+
+        // Memory-only writes are lost when the process exits.
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        assert_eq!(client.read(&k1).await.unwrap(), Some("1".to_string()));
+        assert_eq!(client.read(&k2).await.unwrap(), Some("2".to_string()));
+        assert_eq!(client.read(&k3).await.unwrap(), None);
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let dir = TempDir::new();
+        let options = LSMTreeOptions {
+            sync_interval: Duration::from_millis(10),
+            ..options()
+        };
+        let client = LSMTreeClient::<String>::new(dir.join("tree"), options)
+            .await
+            .unwrap();
+        client
+            .write_with_durability(Key::new(), Some("value".to_string()), Durability::Buffered)
+            .await
+            .unwrap();
+        for _ in 0..100 {
+            if !client.tree.buffers.read().await.buffer.is_dirty() {
+                break;
             }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!client.tree.buffers.read().await.buffer.is_dirty());
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_foreign_directories() {
+        let dir = TempDir::new();
+        let client = LSMTreeClient::<String>::new(dir.join("tree"), options())
+            .await
+            .unwrap();
+        let key = Key::new();
+        client
+            .write(key.clone(), Some("value".to_string()))
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        drop(client);
+
+        // Only stray files are removed when the tree is opened again.
+        let (extra, nested) = (
+            dir.join("tree/extra.d"),
+            dir.join("tree/tables").join(Key::new().hex()),
+        );
+        for d in [&extra, &nested] {
+            create_dir(d).await.unwrap();
+            write(d.join("file"), b"kept").await.unwrap();
+        }
+        write(dir.join("tree/stray"), b"removed").await.unwrap();
+        let client = LSMTreeClient::<String>::new(dir.join("tree"), options())
+            .await
+            .unwrap();
+        assert!(metadata(extra.join("file")).await.is_ok());
+        assert!(metadata(nested.join("file")).await.is_ok());
+        assert!(metadata(dir.join("tree/stray")).await.is_err());
+        assert_eq!(client.read(&key).await.unwrap(), Some("value".to_string()));
+        client.shutdown().await.unwrap();
+    }
+
+    async fn check_ingested(client: &LSMTreeClient<String>, keys: &[Key]) {
+        for (i, k) in keys.iter().enumerate() {
+            let expected = (i != 50).then(|| format!("value{}", i));
+            assert_eq!(client.read(k).await.unwrap(), expected);
+        }
+        assert_eq!(
+            client.read(&Key::from("zzz")).await.unwrap(),
+            Some("kept".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ingest() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        let keys: Vec<_> = (0..100)
+            .map(|i| Key::from(format!("{:03}", i).as_str()))
+            .collect();
+        for k in keys.iter().step_by(10) {
+            client
+                .write(k.clone(), Some("old".to_string()))
+                .await
+                .unwrap();
+        }
+        client
+            .write(Key::from("zzz"), Some("kept".to_string()))
+            .await
+            .unwrap();
+
+        let path = temp.join("ingest.sst");
+        let mut writer = SSTableWriter::create(path.clone(), keys.len(), Compression::None)
+            .await
+            .unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            let value = (i != 50).then(|| format!("value{}", i));
+            writer.write(k.clone(), value).await.unwrap();
         }
-        let mut current = self.tree.first.load_full();
-        loop {
-            let c = match current.as_ref().as_ref() {
-                Some(c) => c,
-                None => {
-                    debug!("Did not find {}.", key.hex());
-                    return None;
+        writer.finish().await.unwrap();
+        let snapshot = client.snapshot().await;
+        client.ingest_sstable(&path).await.unwrap();
+        remove_file(&path).await.unwrap();
+
+        check_ingested(&client, &keys).await;
+        assert_eq!(snapshot.read(&keys[1]).await.unwrap(), None);
+        drop(snapshot);
+
+        // Ingesting the same value again is still seen as a change.
+        let (version, _) = client.read_with_version(&keys[1]).await.unwrap().unwrap();
+        assert!(version > 0);
+        let result = client
+            .transaction(|tx| {
+                let (client, path, key) = (&client, &path, keys[1].clone());
+                async move {
+                    tx.get(&key).await?;
+                    let mut writer = SSTableWriter::create(path.clone(), 1, Compression::None)
+                        .await
+                        .unwrap();
+                    writer
+                        .write(key.clone(), Some("value1".to_string()))
+                        .await?;
+                    writer.finish().await?;
+                    client.ingest_sstable(path).await?;
+                    remove_file(path).await?;
+                    tx.put(key, "changed".to_string());
+                    Ok(())
                 }
-            };
-            if let Some(x) = c.reader().await.unwrap().read(key).await {
-                let data = x.into_data();
-                debug!("Found {}={:?} in table {}.", key.hex(), &data, c.id());
-                break data;
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Conflict)));
+        let (newer, _) = client.read_with_version(&keys[1]).await.unwrap().unwrap();
+        assert!(newer > version);
+
+        // Tables whose entries cannot be read are rejected.
+        let mut writer = SSTableWriter::create(path.clone(), 1, Compression::None)
+            .await
+            .unwrap();
+        writer
+            .write(keys[1].clone(), Some(vec![0xffu8]))
+            .await
+            .unwrap();
+        writer.finish().await.unwrap();
+        assert!(client.ingest_sstable(&path).await.is_err());
+        remove_file(&path).await.unwrap();
+        assert_eq!(
+            client.read(&keys[1]).await.unwrap(),
+            Some("value1".to_string())
+        );
+        client.shutdown().await.unwrap();
+        drop(client);
+
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        check_ingested(&client, &keys).await;
+        assert_eq!(
+            client.read_with_version(&keys[1]).await.unwrap(),
+            Some((newer, "value1".to_string()))
+        );
+        client
+            .write(keys[1].clone(), Some("new".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.read(&keys[1]).await.unwrap(),
+            Some("new".to_string())
+        );
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ingest_under_writes() {
+        let dir = TempDir::new();
+        let client = Arc::new(
+            LSMTreeClient::<String>::new(dir.join("tree"), options())
+                .await
+                .unwrap(),
+        );
+        let keys: Vec<_> = (0..100)
+            .map(|i| Key::from(format!("{:03}", i).as_str()))
+            .collect();
+        let path = dir.join("ingest.sst");
+        let mut writer = SSTableWriter::create(path.clone(), keys.len(), Compression::None)
+            .await
+            .unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            writer
+                .write(k.clone(), Some(format!("value{}", i)))
+                .await
+                .unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        // Writes keep landing in the table's range until it has been added.
+        let stop = Arc::new(AtomicBool::new(false));
+        let task = {
+            let (client, stop, key) = (client.clone(), stop.clone(), keys[50].clone());
+            spawn(async move {
+                let mut i = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    client
+                        .write(key.clone(), Some(format!("write{}", i)))
+                        .await
+                        .unwrap();
+                    i += 1;
+                }
+            })
+        };
+        sleep(Duration::from_millis(50)).await;
+        timeout(Duration::from_secs(10), client.ingest_sstable(&path))
+            .await
+            .expect("ingest never completed")
+            .unwrap();
+        stop.store(true, Ordering::SeqCst);
+        task.await.unwrap();
+
+        assert_eq!(
+            client.read(&keys[1]).await.unwrap(),
+            Some("value1".to_string())
+        );
+        client
+            .write(keys[50].clone(), Some("last".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.read(&keys[50]).await.unwrap(),
+            Some("last".to_string())
+        );
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint() {
+        let temp = TempDir::new();
+        let (dir, dest) = (temp.join("tree"), temp.join("copy"));
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        let keys: Vec<_> = (0..200).map(|_| Key::new()).collect();
+        for (i, k) in keys.iter().enumerate() {
+            client
+                .write(k.clone(), Some(format!("value{}", i)))
+                .await
+                .unwrap();
+        }
+        client.checkpoint(&dest).await.unwrap();
+        assert!(client.checkpoint(&dest).await.is_err());
+
+        // Later writes, and the merges they cause, do not affect the copy.
+        for k in keys.iter() {
+            client.write(k.clone(), None).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+        drop(client);
+        remove_dir_all(dir).await.unwrap();
+
+        let client = LSMTreeClient::<String>::new(dest.clone(), options())
+            .await
+            .unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(client.read(k).await.unwrap(), Some(format!("value{}", i)));
+        }
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_subscribe() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        let (k1, k2) = (Key::from("a"), Key::from("b"));
+        client
+            .write(k1.clone(), Some("1".to_string()))
+            .await
+            .unwrap();
+        client.write(k2.clone(), None).await.unwrap();
+
+        let mut changes = Box::pin(client.subscribe(0).await.unwrap());
+        let mut resumed = Box::pin(client.subscribe(1).await.unwrap());
+        client
+            .write(k1.clone(), Some("3".to_string()))
+            .await
+            .unwrap();
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            let entry = changes.next().await.unwrap().unwrap();
+            seen.push((entry.sequence, entry.key, entry.data.into_data()));
+        }
+        assert_eq!(
+            seen,
+            vec![
+                (1, k1.clone(), Some("1".to_string())),
+                (2, k2.clone(), None),
+                (3, k1.clone(), Some("3".to_string())),
+            ]
+        );
+        assert_eq!(resumed.next().await.unwrap().unwrap().sequence, 2);
+        assert_eq!(resumed.next().await.unwrap().unwrap().sequence, 3);
+
+        // Writes that are never logged are neither sent nor replayed.
+        client
+            .write_with_durability(k2.clone(), Some("4".to_string()), Durability::None)
+            .await
+            .unwrap();
+        client
+            .write(k1.clone(), Some("5".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(changes.next().await.unwrap().unwrap().sequence, 5);
+        let mut replayed = Box::pin(client.subscribe(3).await.unwrap());
+        assert_eq!(replayed.next().await.unwrap().unwrap().sequence, 5);
+
+        // Subscribers that join while writes are being made miss none of them.
+        let client = Arc::new(client);
+        let writer = {
+            let client = client.clone();
+            spawn(async move {
+                for i in 0..200 {
+                    client
+                        .write(Key::new(), Some(format!("value{}", i)))
+                        .await
+                        .unwrap();
+                }
+            })
+        };
+        let mut subscribers = Vec::new();
+        while !writer.is_finished() {
+            let from = client.snapshot().await.sequence();
+            subscribers.push((from, Box::pin(client.subscribe(from).await.unwrap())));
+            tokio::task::yield_now().await;
+        }
+        writer.await.unwrap();
+        assert!(!subscribers.is_empty());
+        for (from, mut changes) in subscribers {
+            for sequence in from + 1..=205 {
+                let entry = timeout(Duration::from_secs(5), changes.next())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(entry.sequence, sequence);
+            }
+        }
+        let client = Arc::try_unwrap(client).ok().unwrap();
+
+        // Writes that have been merged into tables can no longer be replayed.
+        for i in 0..100 {
+            client
+                .write(Key::new(), Some(format!("value{}", i)))
+                .await
+                .unwrap();
+        }
+        let mut retained = true;
+        for _ in 0..100 {
+            retained = client.subscribe(0).await.is_ok();
+            if !retained {
+                break;
             }
-            current = c.next()
+            sleep(Duration::from_millis(10)).await;
         }
+        assert!(!retained);
+
+        client.shutdown().await.unwrap();
     }
 }
@@ -0,0 +1,273 @@
+use std::path::{Path, PathBuf};
+
+use rocket::{
+    serde::{DeserializeOwned, Serialize},
+    tokio::fs::{metadata, read_dir},
+};
+
+use crate::{
+    core::key::Key,
+    sstables::{sstable::SSTable, write_buffer::WriteBuffer},
+    Result,
+};
+
+use super::{lsm_tree::strays, options::LSMTreeOptions, state::State};
+
+/// The outcome of checking a tree with `check`.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub tables: usize,
+    pub entries: u64,
+    /// The WALs named by the state file, and the batches they hold.
+    pub wals: usize,
+    pub batches: usize,
+    /// Batches held by WALs that the state file does not name, which opening
+    /// the tree would not replay.
+    pub orphan_batches: usize,
+    /// Files that opening the tree would delete.
+    pub orphans: Vec<PathBuf>,
+    /// WALs that opening the tree would move to `wal_archive`.
+    pub archived: Vec<PathBuf>,
+    /// Damage that opening the tree would repair, such as a torn record at
+    /// the end of a WAL.
+    pub warnings: Vec<String>,
+    /// Damage that would stop the tree from opening, or lose data.
+    pub errors: Vec<String>,
+}
+
+/// Reads every table and WAL of the tree in `dir` without changing any of
+/// its files, and reports what is wrong with them and what opening it with
+/// `options` would do. The tree should not be open while it is checked.
+pub async fn check<T: Serialize + DeserializeOwned + Clone>(
+    dir: &Path,
+    options: &LSMTreeOptions,
+) -> Result<Report> {
+    options.validate()?;
+    let mut report = Report::default();
+    let state = match State::load(dir).await {
+        Ok(x) => x,
+        Err(e) => {
+            report
+                .errors
+                .push(format!("The state file cannot be read: {}", e));
+            return Ok(report);
+        }
+    };
+    if let Err(e) = options.validate_layout(&state.layout) {
+        report.errors.push(e.to_string());
+    }
+    let tables_dir = dir.join(&state.layout.tables);
+    let wals_dir = dir.join(&state.layout.wals);
+    for path in [&tables_dir, &wals_dir] {
+        if metadata(path).await.is_err() {
+            report
+                .errors
+                .push(format!("Directory {} is missing", path.display()));
+            return Ok(report);
+        }
+    }
+
+    for run in state.runs.iter() {
+        let mut last: Option<Key> = None;
+        for id in run.tables.iter() {
+            report.tables += 1;
+            let table = match SSTable::<T>::new(&tables_dir, id.clone()).await {
+                Ok(x) => x,
+                Err(e) => {
+                    report
+                        .errors
+                        .push(format!("Table {} cannot be opened: {}", id, e));
+                    continue;
+                }
+            };
+            if let Err(e) = table.verify().await {
+                report.errors.push(format!("Table {}: {}", id, e));
+                continue;
+            }
+            report.entries += table.len();
+            if let Some((first, end)) = table.range() {
+                if matches!(&last, Some(k) if k >= first) {
+                    report.errors.push(format!(
+                        "Table {} overlaps the table before it in a level {} run",
+                        id, run.level
+                    ));
+                }
+                last = Some(end.clone());
+            }
+        }
+    }
+
+    let mut wals = state.builders.clone();
+    wals.push(state.wal.clone());
+    for id in wals.iter() {
+        if metadata(wals_dir.join(id).with_extension("wal"))
+            .await
+            .is_err()
+        {
+            report.errors.push(format!("WAL {} is missing", id));
+        }
+    }
+    let mut paths = Vec::new();
+    let mut files = read_dir(&wals_dir).await?;
+    while let Some(x) = files.next_entry().await? {
+        if x.path().extension().is_some_and(|x| x == "wal") {
+            paths.push(x.path());
+        }
+    }
+    paths.sort();
+    for path in paths {
+        let listed = wals
+            .iter()
+            .any(|x| path.file_stem().is_some_and(|y| y == x.as_str()));
+        match WriteBuffer::<T>::read_wal(path.clone()).await {
+            Ok((batches, torn)) if listed => {
+                report.wals += 1;
+                report.batches += batches.len();
+                if torn > 0 {
+                    report.warnings.push(format!(
+                        "{} ends with a torn record of {} bytes, which opening the tree will truncate",
+                        path.display(),
+                        torn
+                    ));
+                }
+            }
+            Ok((batches, _)) => report.orphan_batches += batches.len(),
+            Err(e) if listed => {
+                report
+                    .errors
+                    .push(format!("{} cannot be replayed: {}", path.display(), e))
+            }
+            Err(e) => {
+                report
+                    .warnings
+                    .push(format!("Orphan {} cannot be read: {}", path.display(), e))
+            }
+        }
+    }
+
+    let tables: Vec<_> = state.runs.iter().flat_map(|r| r.tables.clone()).collect();
+    report.orphans.extend(strays(&tables_dir, &tables).await?);
+    for path in strays(&wals_dir, &wals).await? {
+        match (&options.wal_archive, path.extension()) {
+            (Some(_), Some(x)) if x == "wal" => report.archived.push(path),
+            _ => report.orphans.push(path),
+        }
+    }
+    report.orphans.extend(
+        strays(
+            dir,
+            &[
+                "state".to_string(),
+                state.layout.tables.clone(),
+                state.layout.wals.clone(),
+            ],
+        )
+        .await?,
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::tokio::{
+        self,
+        fs::{create_dir, metadata, remove_file, write, OpenOptions},
+        io::AsyncWriteExt,
+        sync::broadcast::channel,
+    };
+
+    use crate::{
+        core::{
+            entry::{Entry, EntryData},
+            key::Key,
+        },
+        lsm_trees::{client::LSMTreeClient, options::LSMTreeOptions, state::State},
+        sstables::{
+            memtable::Sequence,
+            write_buffer::{Durability, WriteBuffer},
+        },
+        testing::{options, TempDir},
+    };
+
+    use super::check;
+
+    #[tokio::test]
+    async fn test_check() {
+        let temp = TempDir::new();
+        let dir = temp.join("tree");
+        let client = LSMTreeClient::<String>::new(dir.clone(), options())
+            .await
+            .unwrap();
+        for i in 0..100 {
+            client
+                .write(Key::new(), Some(format!("value{}", i)))
+                .await
+                .unwrap();
+        }
+        client.wait_for_flush().await;
+        client.shutdown().await.unwrap();
+        drop(client);
+
+        let report = check::<String>(&dir, &options()).await.unwrap();
+        assert!(report.tables > 0 && report.wals > 0);
+        assert_eq!(report.entries + report.batches as u64, 100);
+        assert!(report.errors.is_empty() && report.warnings.is_empty());
+        assert!(report.orphans.is_empty());
+
+        // A WAL left behind by a flush is archived rather than deleted, and
+        // directories such as an archive kept within the tree are left alone.
+        let archive = dir.join("archive");
+        create_dir(&archive).await.unwrap();
+        let orphan = WriteBuffer::create(dir.join("wals"), Sequence::default(), channel(16).0)
+            .await
+            .unwrap();
+        orphan
+            .write(
+                Entry::new(Key::new(), EntryData::Data("value".to_string())),
+                Durability::Sync,
+            )
+            .await
+            .unwrap();
+        let orphan_wal = orphan.close().await.unwrap();
+        let archiving = LSMTreeOptions {
+            wal_archive: Some(archive),
+            ..options()
+        };
+        let orphaned = check::<String>(&dir, &archiving).await.unwrap();
+        assert_eq!(orphaned.batches, report.batches);
+        assert_eq!(orphaned.orphan_batches, 1);
+        assert!(orphaned.orphans.is_empty());
+        assert_eq!(orphaned.archived, vec![orphan_wal.clone()]);
+        let orphaned = check::<String>(&dir, &options()).await.unwrap();
+        assert_eq!(orphaned.orphans, vec![orphan_wal.clone()]);
+        assert!(orphaned.archived.is_empty());
+        remove_file(&orphan_wal).await.unwrap();
+
+        let state = State::load(&dir).await.unwrap();
+
+        let table = dir
+            .join("tables")
+            .join(&state.runs[0].tables[0])
+            .with_extension("sst");
+        let size = metadata(&table).await.unwrap().len();
+        let file = OpenOptions::new().write(true).open(&table).await.unwrap();
+        file.set_len(size / 2).await.unwrap();
+        drop(file);
+        let wal = dir.join("wals").join(&state.wal).with_extension("wal");
+        let mut file = OpenOptions::new().append(true).open(&wal).await.unwrap();
+        file.write_all(&[0, 0, 0]).await.unwrap();
+        drop(file);
+        let orphan = dir.join("tables").join("orphan.sst");
+        write(&orphan, b"").await.unwrap();
+
+        let report = check::<String>(&dir, &options()).await.unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.orphans, vec![orphan]);
+        // Nothing was repaired.
+        assert_eq!(
+            check::<String>(&dir, &options()).await.unwrap().warnings,
+            report.warnings
+        );
+    }
+}
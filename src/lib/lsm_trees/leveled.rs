@@ -0,0 +1,183 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use log::debug;
+use rocket::serde::{DeserializeOwned, Serialize};
+
+use crate::{
+    core::key::Key,
+    sstables::{sstable::SSTable, sstable_builder::SSTableBuilder},
+    Result,
+};
+
+use super::{
+    lsm_tree::LSMTree,
+    options::LeveledOptions,
+    sstable_node::{NextSSTable, SSTableNode},
+};
+
+fn overlaps<T>(table: &SSTable<T>, first: &Key, last: &Key) -> bool {
+    matches!(table.range(), Some((a, b)) if a <= last && first <= b)
+}
+
+/// Merges the newer tables into the overlapping tables of an older run,
+/// returning the new contents of the older run in key order. Tombstones are
+/// dropped if the older run is the last in the chain.
+async fn merge_tables<T: Serialize + DeserializeOwned + Clone>(
+    newer: Vec<Arc<SSTable<T>>>,
+    older: &[Arc<SSTable<T>>],
+    drop_tombstones: bool,
+    tree: &LSMTree<T>,
+    options: &LeveledOptions,
+) -> Result<Vec<Arc<SSTable<T>>>> {
+    let ranges: Vec<_> = newer.iter().filter_map(|t| t.range()).collect();
+    let first = ranges.iter().map(|r| r.0).min();
+    let last = ranges.iter().map(|r| r.1).max();
+    let (overlapping, mut tables): (Vec<_>, Vec<_>) = match (first, last) {
+        (Some(first), Some(last)) => older
+            .iter()
+            .cloned()
+            .partition(|t| overlaps(t, first, last)),
+        _ => (Vec::new(), older.to_vec()),
+    };
+
+    let inputs: Vec<_> = newer
+        .iter()
+        .chain(overlapping.iter())
+        .map(|t| t.as_ref())
+        .collect();
+    let outputs = SSTableBuilder::compact(
+        &inputs,
+        &tree.tables_dir(),
+        Some(options.table_size),
+        drop_tombstones,
+        tree.options.compression,
+    )
+    .await?;
+    debug!(
+        "Compacted {} tables into {} tables",
+        inputs.len(),
+        outputs.len()
+    );
+    for table in outputs {
+        tables.push(SSTableNode::register(table, &tree.heap));
+    }
+    tables.retain(|t| t.range().is_some());
+    tables.sort_by(|a, b| a.range().unwrap().0.cmp(b.range().unwrap().0));
+    Ok(tables)
+}
+
+/// Creates a run from the tables, or skips it if there are none.
+fn new_node<T>(
+    level: usize,
+    tables: Vec<Arc<SSTable<T>>>,
+    next: Arc<Option<SSTableNode<T>>>,
+) -> Arc<Option<SSTableNode<T>>> {
+    if tables.is_empty() {
+        next
+    } else {
+        SSTableNode::new(level, tables, ArcSwap::from(next))
+    }
+}
+
+/// Merges the level 0 runs at the start of the chain into level 1, once
+/// there are enough of them.
+async fn compact_level0<T: Serialize + DeserializeOwned + Clone>(
+    node: &SSTableNode<T>,
+    tree: &LSMTree<T>,
+    options: &LeveledOptions,
+) -> Result<Option<Arc<Option<SSTableNode<T>>>>> {
+    let mut level0 = node.tables().to_vec();
+    let mut count = 1;
+    let mut rest = node.next();
+    loop {
+        let next = match rest.as_ref() {
+            Some(x) if x.level() == 0 => {
+                level0.extend(x.tables().iter().cloned());
+                count += 1;
+                x.next()
+            }
+            _ => break,
+        };
+        rest = next;
+    }
+    if count < options.level0_tables {
+        return Ok(None);
+    }
+
+    let (level1, after) = match rest.as_ref() {
+        Some(x) if x.level() == 1 => (x.tables().to_vec(), x.next()),
+        _ => (Vec::new(), rest.clone()),
+    };
+    debug!("Compacting {} level 0 runs into level 1", count);
+    let tables = merge_tables(level0, &level1, after.is_none(), tree, options).await?;
+    Ok(Some(new_node(1, tables, after)))
+}
+
+/// Moves a table from a level that has outgrown its target size into the
+/// level below it.
+async fn compact_run<T: Serialize + DeserializeOwned + Clone>(
+    node: &SSTableNode<T>,
+    tree: &LSMTree<T>,
+    options: &LeveledOptions,
+) -> Result<Option<Arc<Option<SSTableNode<T>>>>> {
+    let level = node.level();
+    if node.size() <= options.level_size(level) {
+        return Ok(None);
+    }
+
+    let (picked, remaining) = match node.tables().split_first() {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let next = node.next();
+    let (older, after) = match next.as_ref() {
+        Some(x) if x.level() == level + 1 => (x.tables().to_vec(), x.next()),
+        _ => (Vec::new(), next.clone()),
+    };
+    debug!(
+        "Compacting table {} from level {} into level {}",
+        picked.id(),
+        level,
+        level + 1
+    );
+    let last = after.is_none();
+    let tables = merge_tables(vec![picked.clone()], &older, last, tree, options).await?;
+    let lower = new_node(level + 1, tables, after);
+    Ok(Some(new_node(level, remaining.to_vec(), lower)))
+}
+
+/// Compacts the run stored in `current` until it needs no more work,
+/// returning the final run.
+async fn compact_node<T: Serialize + DeserializeOwned + Clone>(
+    current: &NextSSTable<T>,
+    tree: &LSMTree<T>,
+    options: &LeveledOptions,
+) -> Result<Arc<Option<SSTableNode<T>>>> {
+    loop {
+        let node = current.load_full();
+        let replacement = match node.as_ref() {
+            Some(x) if x.level() == 0 => compact_level0(x, tree, options).await?,
+            Some(x) => compact_run(x, tree, options).await?,
+            None => None,
+        };
+        match replacement {
+            Some(x) => current.store(x),
+            None => return Ok(node),
+        }
+    }
+}
+
+/// Walks the chain of runs, compacting any level that has exceeded its
+/// limits. Each replacement returns the same results as the runs it
+/// replaces, so concurrent readers see a consistent tree throughout.
+pub(super) async fn compact<T: Serialize + DeserializeOwned + Clone>(
+    tree: &LSMTree<T>,
+    options: &LeveledOptions,
+) -> Result<()> {
+    let mut current = compact_node(tree.first.as_ref(), tree, options).await?;
+    while let Some(c) = current.as_ref() {
+        current = compact_node(c.next_lock(), tree, options).await?;
+    }
+    Ok(())
+}
@@ -1,21 +1,56 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::{collections::VecDeque, sync::Arc};
 
 use arc_swap::ArcSwap;
+use log::warn;
 use parking_lot::Mutex;
+use rocket::futures::{stream, Stream, StreamExt};
 use rocket::serde::{DeserializeOwned, Serialize};
-use rocket::tokio::fs::{create_dir, read_dir, remove_dir, remove_file};
-use rocket::tokio::sync::RwLock;
+use rocket::tokio::fs::{
+    copy, create_dir, hard_link, read_dir, remove_dir_all, remove_file, symlink_metadata, File,
+};
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::tokio::sync::{oneshot, Notify, RwLock};
 
+use crate::core::entry::Entry;
+use crate::persistance::wal;
+use crate::sstables::memtable::Sequence;
 use crate::sstables::sstable::SSTable;
 use crate::sstables::sstable_builder::SSTableBuilder;
-use crate::sstables::write_buffer::WriteBuffer;
+use crate::sstables::write_buffer::{Changes, WriteBuffer};
+use crate::{Error, Result};
 
+use super::options::LSMTreeOptions;
+use super::snapshot::Snapshot;
 use super::sstable_node::{NextSSTable, SSTableNode};
-use super::state::State;
+use super::state::{Run, State};
 
-pub type Heap<T> = Arc<Mutex<HashMap<String, Arc<Option<SSTableNode<T>>>>>>;
+/// Every table referenced by the tree, keyed by id. A table is deleted once
+/// the heap holds the only reference to it.
+pub type Heap<T> = Arc<Mutex<HashMap<String, Arc<SSTable<T>>>>>;
+
+/// A table waiting to be spliced into the tree, and the caller to notify once
+/// it has been.
+#[derive(Debug)]
+pub(super) struct Ingest<T> {
+    pub(super) table: SSTable<T>,
+    /// Set for a table sent by a leader, which keeps the sequences it was
+    /// given there rather than taking the next one.
+    pub(super) replica: bool,
+    pub(super) sender: oneshot::Sender<Result<()>>,
+}
+
+/// The error given when a table is ingested by a tree with followers.
+fn leading() -> Error {
+    Error::InvalidArgument("Tables cannot be ingested while the tree has followers".to_string())
+}
+
+/// The error given when a tree that follows a leader is written to.
+fn following() -> Error {
+    Error::InvalidArgument("A tree that follows a leader only applies its writes".to_string())
+}
 
 #[derive(Debug)]
 pub(super) struct Buffers<T: Serialize + DeserializeOwned> {
@@ -29,105 +64,475 @@ pub struct LSMTree<T: Serialize + DeserializeOwned> {
     pub(super) buffers: Arc<RwLock<Buffers<T>>>,
     pub(super) first: Arc<NextSSTable<T>>,
     pub(super) heap: Heap<T>,
+    /// The sequence number of the most recent write.
+    pub(super) sequence: Sequence,
+    /// Publishes each write once it has been committed.
+    pub(super) changes: Changes<T>,
+    pub(super) options: LSMTreeOptions,
+    /// Notified whenever a queued builder has been written to disk.
+    pub(super) flushed: Notify,
+    /// Tables waiting to be ingested by the background service, oldest first.
+    pub(super) ingests: Mutex<Vec<Ingest<T>>>,
+    /// Wakes the background service when a table is queued for ingestion.
+    pub(super) ingested: Notify,
+    /// Set once the tree has been shut down, after which writes are rejected.
+    closed: AtomicBool,
+    /// Set once the tree sends its writes to followers, after which tables
+    /// are rejected, as they would never reach them.
+    leading: AtomicBool,
+    /// Set once the tree applies the writes of a leader, after which any
+    /// other write is rejected, as it would diverge from the leader.
+    following: AtomicBool,
+    /// Wakes the background service when the tree is shut down.
+    pub(super) closing: Notify,
+}
+
+/// Lists the entries of the directory whose names are not in `allowed`,
+/// ignoring the extension of regular files.
+async fn unlisted(dir: &Path, allowed: &[String]) -> Result<Vec<PathBuf>> {
+    let mut unlisted = Vec::new();
+    let mut files = read_dir(dir).await?;
+    while let Some(x) = files.next_entry().await? {
+        let mut name = PathBuf::from(x.file_name());
+        if x.file_type().await?.is_file() {
+            name.set_extension("");
+        }
+
+        if !allowed.contains(&name.to_string_lossy().to_string()) {
+            unlisted.push(x.path());
+        }
+    }
+    Ok(unlisted)
+}
+
+/// Lists the entries of the directory that opening the tree would remove:
+/// those not named in `allowed`, other than directories, which are left
+/// alone as the tree never creates any but those of its layout.
+pub(super) async fn strays(dir: &Path, allowed: &[String]) -> Result<Vec<PathBuf>> {
+    let mut strays = Vec::new();
+    for path in unlisted(dir, allowed).await? {
+        if !symlink_metadata(&path).await?.is_dir() {
+            strays.push(path);
+        }
+    }
+    Ok(strays)
+}
+
+/// Removes every stray file in the directory. If an archive is given, WALs
+/// are moved there instead.
+async fn drain_dir(dir: &Path, allowed: &[String], archive: Option<&Path>) -> Result<()> {
+    for path in strays(dir, allowed).await? {
+        if let (Some(archive), Some("wal")) = (archive, path.extension().and_then(|x| x.to_str())) {
+            wal::archive(&path, archive).await?
+        } else {
+            remove_file(&path).await?
+        }
+    }
+    Ok(())
+}
+
+/// Hard-links the file to the target, falling back to a synced copy where
+/// links are not possible, such as across file systems.
+pub(super) async fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    if hard_link(from, to).await.is_err() {
+        copy(from, to).await?;
+        File::open(to).await?.sync_all().await?;
+    }
+    Ok(())
 }
 
-async fn drain_dir(dir: &Path, allowed: &[String]) {
-    let mut files = read_dir(dir).await.unwrap();
-    while let Some(x) = files.next_entry().await.unwrap() {
-        let name = PathBuf::from(x.file_name()).with_extension("");
-        let name = name.file_name().unwrap();
-
-        if !allowed.contains(&name.to_str().unwrap().to_string()) {
-            if x.file_type().await.unwrap().is_dir() {
-                remove_dir(x.path()).await.unwrap()
-            } else {
-                remove_file(x.path()).await.unwrap()
+/// Lists the runs of the chain starting at the node, from newest to oldest,
+/// along with the sequences of the ingested tables in them.
+fn runs_of<T>(mut current: Arc<Option<SSTableNode<T>>>) -> (Vec<Run>, Vec<(String, u64)>) {
+    let mut runs = Vec::new();
+    let mut ingested = Vec::new();
+    while let Some(x) = current.as_ref() {
+        runs.push(Run {
+            level: x.level(),
+            tables: x.tables().iter().map(|t| t.id().to_string()).collect(),
+        });
+        for t in x.tables() {
+            if let Some(sequence) = t.sequence() {
+                ingested.push((t.id().to_string(), sequence));
             }
         }
+        current = x.next();
     }
+    (runs, ingested)
 }
 
 impl<T: Serialize + DeserializeOwned + Clone> LSMTree<T> {
-    pub(super) async fn new(dir: PathBuf) -> LSMTree<T> {
-        create_dir(&dir).await.unwrap();
-        create_dir(dir.join("tables")).await.unwrap();
-        create_dir(dir.join("wals")).await.unwrap();
+    pub(super) async fn new(dir: PathBuf, options: LSMTreeOptions) -> Result<LSMTree<T>> {
+        options.validate()?;
+        create_dir(&dir).await?;
+        create_dir(dir.join(&options.layout.tables)).await?;
+        create_dir(dir.join(&options.layout.wals)).await?;
+        let sequence = Sequence::default();
+        let (changes, _) = broadcast::channel(options.subscriber_capacity);
         let tree = LSMTree {
             dir: dir.clone(),
             buffers: Arc::new(RwLock::new(Buffers {
-                buffer: WriteBuffer::create(dir.join("wals")).await,
+                buffer: WriteBuffer::create(
+                    dir.join(&options.layout.wals),
+                    sequence.clone(),
+                    changes.clone(),
+                )
+                .await?,
                 builders: VecDeque::new(),
             })),
             first: Arc::new(ArcSwap::from_pointee(None)),
             heap: Arc::new(Mutex::new(HashMap::new())),
+            sequence,
+            changes,
+            options,
+            flushed: Notify::new(),
+            ingests: Mutex::new(Vec::new()),
+            ingested: Notify::new(),
+            closed: AtomicBool::new(false),
+            leading: AtomicBool::new(false),
+            following: AtomicBool::new(false),
+            closing: Notify::new(),
         };
-        tree.state().await.save(&dir).await;
-        tree
+        tree.state().await.save(&dir).await?;
+        Ok(tree)
     }
 
-    pub(super) async fn load(dir: PathBuf) -> LSMTree<T> {
-        let s = State::load(&dir).await;
+    pub(super) async fn load(dir: PathBuf, options: LSMTreeOptions) -> Result<LSMTree<T>> {
+        options.validate()?;
+        let s = State::load(&dir).await?;
+        options.validate_layout(&s.layout)?;
+        let tables_dir = dir.join(&options.layout.tables);
+        let wals_dir = dir.join(&options.layout.wals);
 
-        drain_dir(&dir.join("tables"), &s.tables).await;
+        let tables: Vec<_> = s.runs.iter().flat_map(|r| r.tables.clone()).collect();
+        drain_dir(&tables_dir, &tables, None).await?;
         let mut wals = s.builders.clone();
         wals.push(s.wal.clone());
-        drain_dir(&dir.join("wals"), &wals).await;
+        // A crash just after a buffer was flushed leaves its WAL behind, and
+        // it must still be archived.
+        drain_dir(&wals_dir, &wals, options.wal_archive.as_deref()).await?;
         drain_dir(
             &dir,
             &[
                 "state".to_string(),
-                "tables".to_string(),
-                "wals".to_string(),
+                options.layout.tables.clone(),
+                options.layout.wals.clone(),
             ],
+            None,
         )
-        .await;
+        .await?;
 
+        let sequence = Arc::new(AtomicU64::new(s.sequence));
+        let (changes, _) = broadcast::channel(options.subscriber_capacity);
         let mut builders = VecDeque::new();
-        for id in s.builders {
-            let w = WriteBuffer::from(dir.clone(), id).await.to_builder().await;
-            builders.push_back(w);
+        for id in s.builders.into_iter().rev() {
+            let w = WriteBuffer::from(wals_dir.clone(), id, sequence.clone(), changes.clone())
+                .await?
+                .to_builder()
+                .await?;
+            builders.push_front(w);
         }
         let mut first = ArcSwap::from_pointee(None);
         let heap = Arc::new(Mutex::new(HashMap::new()));
-        for id in s.tables.into_iter().rev() {
-            let table = SSTableNode::new(
-                SSTable::new(&dir.join("tables"), id.clone()).await,
-                first,
-                &heap,
-            );
-            first = ArcSwap::from(table);
+        let ingested: HashMap<_, _> = s.ingested.into_iter().collect();
+        for run in s.runs.into_iter().rev() {
+            let mut tables = Vec::new();
+            for id in run.tables {
+                let sequence = ingested.get(&id).copied();
+                let mut table = SSTable::new(&tables_dir, id).await?;
+                if let Some(sequence) = sequence {
+                    table.set_sequence(sequence);
+                }
+                tables.push(SSTableNode::register(table, &heap));
+            }
+            first = ArcSwap::from(SSTableNode::new(run.level, tables, first));
         }
 
-        LSMTree {
+        Ok(LSMTree {
             buffers: Arc::new(RwLock::new(Buffers {
-                buffer: WriteBuffer::open(dir.join("wals"), s.wal).await,
+                buffer: WriteBuffer::open(wals_dir, s.wal, sequence.clone(), changes.clone())
+                    .await?,
                 builders,
             })),
             first: Arc::new(first),
             heap,
+            sequence,
+            changes,
             dir,
+            options,
+            flushed: Notify::new(),
+            ingests: Mutex::new(Vec::new()),
+            ingested: Notify::new(),
+            closed: AtomicBool::new(false),
+            leading: AtomicBool::new(false),
+            following: AtomicBool::new(false),
+            closing: Notify::new(),
+        })
+    }
+
+    pub(super) fn tables_dir(&self) -> PathBuf {
+        self.dir.join(&self.options.layout.tables)
+    }
+
+    pub(super) fn wals_dir(&self) -> PathBuf {
+        self.dir.join(&self.options.layout.wals)
+    }
+
+    pub(super) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Fails unless clients may write to the tree: it must not have been
+    /// shut down, nor follow a leader.
+    pub(super) fn check_writable(&self) -> Result<()> {
+        if self.is_closed() {
+            return Err(Error::Closed);
+        }
+        if self.following.load(Ordering::SeqCst) {
+            return Err(following());
         }
+        Ok(())
     }
 
-    pub(super) async fn state(&self) -> State {
-        let mut nodes = Vec::new();
-        let mut current = self.first.load_full();
+    /// Rejects any further writes, and tells the background service to stop.
+    /// Writes that are already in progress complete first, but tables that
+    /// are still waiting to be ingested are abandoned.
+    pub(super) async fn close(&self) {
+        {
+            let _lock = self.buffers.write().await;
+            self.closed.store(true, Ordering::SeqCst);
+        }
+        for ingest in self.ingests.lock().drain(..) {
+            let _ = ingest.sender.send(Err(Error::Closed));
+        }
+        self.closing.notify_one();
+        self.flushed.notify_waiters();
+    }
+
+    /// Waits until the write buffer has room, or until it can be swapped out
+    /// because the queue of builders is not full.
+    pub(super) async fn throttle(&self) -> Result<()> {
         loop {
-            current = match current.as_ref() {
-                None => break,
-                Some(x) => {
-                    nodes.push(x.table().id().to_string());
-                    x.next()
+            let flushed = self.flushed.notified();
+            {
+                let lock = self.buffers.read().await;
+                if self.is_closed() {
+                    return Err(Error::Closed);
+                }
+                if lock.buffer.size_bytes() < self.options.memtable_size
+                    || lock.builders.len() < self.options.max_queued_builders
+                {
+                    return Ok(());
                 }
-            };
+            }
+            flushed.await;
         }
+    }
 
-        let lock = self.buffers.read().await;
+    /// Rejects any further tables before the tree starts sending its writes
+    /// to followers. Tables still waiting are rejected when the background
+    /// service next tries to splice them.
+    pub(super) async fn lead(&self) {
+        let _lock = self.buffers.write().await;
+        self.leading.store(true, Ordering::SeqCst);
+    }
+
+    /// Rejects any further writes but those applied from a leader, and any
+    /// table but those sent by it. Tables still waiting are rejected when the
+    /// background service next tries to splice them.
+    pub(super) async fn follow(&self) {
+        let _lock = self.buffers.write().await;
+        self.following.store(true, Ordering::SeqCst);
+    }
+
+    /// Fails if a table can no longer be ingested: any table once the tree
+    /// has followers, and any not sent by its leader once it has one.
+    pub(super) fn check_ingest(&self, replica: bool) -> Result<()> {
+        if self.leading.load(Ordering::SeqCst) {
+            return Err(leading());
+        }
+        if !replica && self.following.load(Ordering::SeqCst) {
+            return Err(following());
+        }
+        Ok(())
+    }
+
+    /// Queues a table that has been linked into the tables directory to be
+    /// spliced into the tree by the background service, and waits until it
+    /// has been.
+    pub(super) async fn ingest(&self, table: SSTable<T>, replica: bool) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let lock = self.buffers.read().await;
+            if self.is_closed() {
+                return Err(Error::Closed);
+            }
+            if let Err(e) = self.check_ingest(replica) {
+                drop(lock);
+                table.delete().await?;
+                return Err(e);
+            }
+            self.ingests.lock().push(Ingest {
+                table,
+                replica,
+                sender,
+            });
+        }
+        self.ingested.notify_one();
+        receiver.await.unwrap_or(Err(Error::Closed))
+    }
+
+    /// Captures the current contents of the tree. Writes made afterwards are
+    /// not visible through the snapshot.
+    pub(super) async fn snapshot(&self) -> Snapshot<T> {
+        self.snapshot_of(&*self.buffers.read().await)
+    }
+
+    /// Captures the contents of the tree while the caller holds the buffers
+    /// lock.
+    pub(super) fn snapshot_of(&self, buffers: &Buffers<T>) -> Snapshot<T> {
+        let sequence = self.sequence.load(Ordering::SeqCst);
+        let mut memtables = vec![buffers.buffer.memtable()];
+        memtables.extend(buffers.builders.iter().map(|b| b.memtable()));
+        Snapshot::new(sequence, memtables, self.first.load_full())
+    }
+
+    /// Returns the sequence of the newest write that may have been merged
+    /// into a table. Every later write is still held in a WAL.
+    pub(super) fn retained(&self, buffers: &Buffers<T>) -> u64 {
+        match buffers.builders.back() {
+            Some(b) => b.start(),
+            None => buffers.buffer.start(),
+        }
+    }
+
+    /// Returns the batches committed after `from`, replayed from the buffers,
+    /// followed by each batch as it is committed. Fails if some of those
+    /// batches are no longer held in a WAL.
+    pub(super) fn changes_of(
+        &self,
+        buffers: &Buffers<T>,
+        from: u64,
+    ) -> Result<impl Stream<Item = Result<Arc<Vec<Entry<T>>>>>> {
+        // Subscribing first means that any batch newer than `until` is sure
+        // to be received.
+        let receiver = self.changes.subscribe();
+        let until = self.sequence.load(Ordering::SeqCst);
+        let retained = self.retained(buffers);
+        if from < retained {
+            return Err(Error::InvalidArgument(format!(
+                "Writes before sequence {} are no longer retained",
+                retained + 1
+            )));
+        }
+        let mut entries = Vec::new();
+        for b in buffers.builders.iter().rev() {
+            entries.extend(b.memtable().changes(from, until));
+        }
+        entries.extend(buffers.buffer.memtable().changes(from, until));
+        let mut replay: Vec<Vec<Entry<T>>> = Vec::new();
+        for entry in entries {
+            match replay.last_mut() {
+                Some(batch) if batch[0].sequence == entry.sequence => batch.push(entry),
+                _ => replay.push(vec![entry]),
+            }
+        }
+
+        let until = until.max(from);
+        let live = stream::unfold(Some(receiver), move |receiver| async move {
+            let mut receiver = receiver?;
+            loop {
+                match receiver.recv().await {
+                    // Batches up to `until` were replayed from the buffers.
+                    Ok(x) if x[0].sequence <= until => continue,
+                    Ok(x) => return Some((Ok(x), Some(receiver))),
+                    Err(RecvError::Lagged(_)) => return Some((Err(Error::Lagged), None)),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(stream::iter(replay.into_iter().map(|x| Ok(Arc::new(x)))).chain(live))
+    }
+
+    /// Writes a copy of the tree to `dest`, which must not exist, that can be
+    /// opened as a tree of its own. Tables and the WALs of queued builders
+    /// never change, so they are hard-linked where possible, while the WAL
+    /// of the write buffer is copied. Whatever was written to `dest` is
+    /// removed if the copy fails, and the error of the copy is returned.
+    pub(super) async fn checkpoint(&self, dest: &Path) -> Result<()> {
+        create_dir(dest).await?;
+        let result = self.write_checkpoint(dest).await;
+        if result.is_err() {
+            if let Err(e) = remove_dir_all(dest).await {
+                warn!(
+                    "Failed to remove partial checkpoint {}: {}",
+                    dest.display(),
+                    e
+                );
+            }
+        }
+        result
+    }
+
+    async fn write_checkpoint(&self, dest: &Path) -> Result<()> {
+        let layout = &self.options.layout;
+        let (tables_dir, wals_dir) = (dest.join(&layout.tables), dest.join(&layout.wals));
+        create_dir(&tables_dir).await?;
+        create_dir(&wals_dir).await?;
+
+        // Holding the first node keeps its tables from being deleted once the
+        // lock is released.
+        let (first, state) = {
+            let lock = self.buffers.read().await;
+            let wal = lock.buffer.id().to_string();
+            // Writes may continue once the WAL is copied, so the sequence is
+            // taken along with the copy.
+            let sequence = lock
+                .buffer
+                .copy_wal(&wals_dir.join(&wal).with_extension("wal"))
+                .await?;
+            let mut builders = Vec::new();
+            for b in lock.builders.iter() {
+                let name = PathBuf::from(b.id()).with_extension("wal");
+                link_or_copy(&self.wals_dir().join(&name), &wals_dir.join(&name)).await?;
+                builders.push(b.id().to_string());
+            }
+            let first = self.first.load_full();
+            let (runs, ingested) = runs_of(first.clone());
+            let state = State::new(wal, builders, runs, layout.clone(), sequence, ingested);
+            (first, state)
+        };
+
+        let mut current = first;
+        while let Some(x) = current.as_ref() {
+            for table in x.tables() {
+                for file in table.files() {
+                    link_or_copy(file, &tables_dir.join(file.file_name().unwrap())).await?;
+                }
+            }
+            current = x.next();
+        }
+        state.save(dest).await
+    }
+
+    pub(super) async fn state(&self) -> State {
+        self.state_of(&*self.buffers.read().await)
+    }
 
+    /// Returns the state of the tree while the lock on `buffers` is held.
+    pub(super) fn state_of(&self, buffers: &Buffers<T>) -> State {
+        let (runs, ingested) = runs_of(self.first.load_full());
         State::new(
-            lock.buffer.id().to_string(),
-            lock.builders.iter().map(|x| x.id().to_string()).collect(),
-            nodes,
+            buffers.buffer.id().to_string(),
+            buffers
+                .builders
+                .iter()
+                .map(|x| x.id().to_string())
+                .collect(),
+            runs,
+            self.options.layout.clone(),
+            self.sequence.load(Ordering::SeqCst),
+            ingested,
         )
     }
 }
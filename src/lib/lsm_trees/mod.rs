@@ -1,5 +1,13 @@
 pub mod lsm_tree;
+pub mod options;
+mod leveled;
+mod scan;
 pub mod client;
 pub mod sstable_node;
 pub mod state;
 pub mod service;
+pub mod snapshot;
+pub mod transaction;
+pub mod replication;
+pub mod restore;
+pub mod fsck;
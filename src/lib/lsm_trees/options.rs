@@ -0,0 +1,223 @@
+use std::{
+    path::{is_separator, PathBuf},
+    time::Duration,
+};
+
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::{
+    sstables::{block::Compression, write_buffer::Durability},
+    Error, Result,
+};
+
+/// Names of the subdirectories of a tree. These are recorded in the `State`
+/// file, and cannot be changed once the tree has been created.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub struct DirectoryLayout {
+    pub tables: String,
+    pub wals: String,
+}
+
+impl Default for DirectoryLayout {
+    fn default() -> Self {
+        DirectoryLayout {
+            tables: "tables".to_string(),
+            wals: "wals".to_string(),
+        }
+    }
+}
+
+/// Parameters of the leveled compaction strategy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeveledOptions {
+    /// Number of tables in level 0 that triggers a compaction into level 1.
+    pub level0_tables: usize,
+    /// Target size of level 1 in bytes.
+    pub level1_size: u64,
+    /// Each level is this many times larger than the one before it.
+    pub level_multiplier: u64,
+    /// Approximate size in bytes of the tables written by compactions.
+    pub table_size: u64,
+}
+
+impl Default for LeveledOptions {
+    fn default() -> Self {
+        LeveledOptions {
+            level0_tables: 4,
+            level1_size: 10 << 20,
+            level_multiplier: 10,
+            table_size: 2 << 20,
+        }
+    }
+}
+
+impl LeveledOptions {
+    /// Returns the target size in bytes of a level other than level 0.
+    pub fn level_size(&self, level: usize) -> u64 {
+        self.level1_size
+            .saturating_mul(self.level_multiplier.saturating_pow(level as u32 - 1))
+    }
+}
+
+/// How tables are merged in the background.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompactionStrategy {
+    /// Each flushed buffer is pushed onto the front of the chain of tables,
+    /// and adjacent tables are merged whenever the older one is smaller than
+    /// `merge_ratio` times the size of the newer one.
+    Tiered,
+    /// Flushed buffers form an overlapping level 0, which is merged into
+    /// levels of non-overlapping tables that grow geometrically in size.
+    Leveled(LeveledOptions),
+}
+
+/// Tuning parameters for an `LSMTree`. Apart from the `layout`, these may be
+/// changed freely between runs.
+#[derive(Debug, Clone)]
+pub struct LSMTreeOptions {
+    /// Approximate size in bytes at which the write buffer is flushed.
+    pub memtable_size: usize,
+    /// A table is merged into the next one when the next is smaller than this
+    /// fraction of its size.
+    pub merge_ratio: f64,
+    /// Maximum number of flushed buffers waiting to be written to disk.
+    /// Writes are delayed while the queue is full.
+    pub max_queued_builders: usize,
+    /// How long the background service sleeps when there is no work to do.
+    pub poll_interval: Duration,
+    pub compaction: CompactionStrategy,
+    /// How the blocks of newly written tables are compressed. Existing tables
+    /// are read with whatever compression they were written with.
+    pub compression: Compression,
+    /// How writes that do not specify a durability are persisted.
+    pub durability: Durability,
+    /// How often writes made with `Durability::Buffered` are synced.
+    pub sync_interval: Duration,
+    /// Number of changes held for each subscriber before it is considered to
+    /// have fallen behind.
+    pub subscriber_capacity: usize,
+    /// Directory that the WALs of flushed buffers are moved to, rather than
+    /// being deleted, so that a backup can later be rolled forward with
+    /// `restore`. Nothing is ever removed from it.
+    pub wal_archive: Option<PathBuf>,
+    pub layout: DirectoryLayout,
+}
+
+impl Default for LSMTreeOptions {
+    fn default() -> Self {
+        LSMTreeOptions {
+            memtable_size: 4 << 20,
+            merge_ratio: 0.75,
+            max_queued_builders: 4,
+            poll_interval: Duration::from_millis(1000),
+            compaction: CompactionStrategy::Tiered,
+            compression: Compression::None,
+            durability: Durability::Sync,
+            sync_interval: Duration::from_millis(1000),
+            subscriber_capacity: 4096,
+            wal_archive: None,
+            layout: DirectoryLayout::default(),
+        }
+    }
+}
+
+impl LSMTreeOptions {
+    pub fn validate(&self) -> Result<()> {
+        if self.memtable_size == 0 {
+            return Err(Error::InvalidOptions(
+                "memtable_size must be positive".to_string(),
+            ));
+        }
+        if !(self.merge_ratio > 0.0 && self.merge_ratio <= 1.0) {
+            return Err(Error::InvalidOptions(
+                "merge_ratio must be in (0, 1]".to_string(),
+            ));
+        }
+        if self.max_queued_builders == 0 {
+            return Err(Error::InvalidOptions(
+                "max_queued_builders must be positive".to_string(),
+            ));
+        }
+        if self.subscriber_capacity == 0 {
+            return Err(Error::InvalidOptions(
+                "subscriber_capacity must be positive".to_string(),
+            ));
+        }
+        if self.sync_interval.is_zero() {
+            return Err(Error::InvalidOptions(
+                "sync_interval must be positive".to_string(),
+            ));
+        }
+        if let CompactionStrategy::Leveled(leveled) = &self.compaction {
+            if leveled.level0_tables == 0 || leveled.level_multiplier < 2 {
+                return Err(Error::InvalidOptions(
+                    "level0_tables must be positive and level_multiplier at least 2".to_string(),
+                ));
+            }
+        }
+        for name in [&self.layout.tables, &self.layout.wals] {
+            // Names are matched against file stems, and must not clash with
+            // the state file or reach outside the tree's directory.
+            if name.is_empty() || name == "state" || name.contains(|c| c == '.' || is_separator(c))
+            {
+                return Err(Error::InvalidOptions(format!(
+                    "{:?} is not a valid directory name",
+                    name
+                )));
+            }
+        }
+        if self.layout.tables == self.layout.wals {
+            return Err(Error::InvalidOptions(
+                "tables and wals must be different directories".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that the options are compatible with an existing tree.
+    pub fn validate_layout(&self, existing: &DirectoryLayout) -> Result<()> {
+        if &self.layout != existing {
+            return Err(Error::InvalidOptions(format!(
+                "Directory layout {:?} does not match existing layout {:?}",
+                self.layout, existing
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DirectoryLayout, LSMTreeOptions};
+
+    #[test]
+    fn test_validate() {
+        let options = LSMTreeOptions::default();
+        assert!(options.validate().is_ok());
+        assert!(options.validate_layout(&DirectoryLayout::default()).is_ok());
+
+        let moved = DirectoryLayout {
+            tables: "sstables".to_string(),
+            ..DirectoryLayout::default()
+        };
+        assert!(options.validate_layout(&moved).is_err());
+
+        let bad = LSMTreeOptions {
+            merge_ratio: 1.5,
+            ..LSMTreeOptions::default()
+        };
+        assert!(bad.validate().is_err());
+
+        for name in ["", "state", "..", "tables.old", "a/b"] {
+            let bad = LSMTreeOptions {
+                layout: DirectoryLayout {
+                    tables: name.to_string(),
+                    ..DirectoryLayout::default()
+                },
+                ..LSMTreeOptions::default()
+            };
+            assert!(bad.validate().is_err(), "{:?} was accepted", name);
+        }
+    }
+}
@@ -0,0 +1,567 @@
+use std::{
+    fmt::Debug,
+    io,
+    sync::{atomic::Ordering, Arc},
+};
+
+use log::{info, warn};
+use rocket::{
+    futures::StreamExt,
+    serde::{Deserialize, DeserializeOwned, Serialize},
+    tokio::{
+        fs::{remove_file, File},
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream, ToSocketAddrs},
+        spawn,
+    },
+};
+
+use crate::{
+    core::{entry::Entry, key::Key},
+    sstables::sstable::SSTable,
+    Error, Result,
+};
+
+use super::client::LSMTreeClient;
+
+/// Size of the pieces that tables, and batches too large for a single
+/// message, are sent in.
+const CHUNK_SIZE: usize = 1 << 20;
+/// Largest message that may be sent: a chunk along with the encoding of the
+/// message around it. A longer frame can only come from a damaged stream, and
+/// is refused rather than allocated.
+const MAX_MESSAGE_SIZE: u64 = CHUNK_SIZE as u64 + 1024;
+/// Largest batch that may be sent in chunks. A follower refuses to hold more
+/// than this for a single batch.
+const MAX_BATCH_SIZE: usize = 256 << 20;
+
+/// A message sent by a leader to a follower. Each is framed by its length as
+/// a big-endian `u64`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+enum Message<T> {
+    /// The follower is missing writes that have been merged into tables. The
+    /// leader's tables follow, oldest first, and hold every write up to
+    /// `sequence`. Each table is listed with the sequence it was given if it
+    /// was ingested, as its entries hold no sequences of their own.
+    CatchUp {
+        sequence: u64,
+        tables: Vec<Option<u64>>,
+    },
+    /// The next piece of the table being sent.
+    Chunk(Vec<u8>),
+    /// The end of the table being sent.
+    EndOfTable,
+    /// A batch of writes, to be applied atomically.
+    Batch(Vec<Entry<T>>),
+    /// The end of a batch that was sent in chunks, as it was too large for a
+    /// single message.
+    EndOfBatch,
+}
+
+async fn send<T: Serialize>(socket: &mut TcpStream, message: &Message<T>) -> Result<()> {
+    let bytes = bincode::serialize(message)?;
+    if bytes.len() as u64 > MAX_MESSAGE_SIZE {
+        return Err(Error::InvalidArgument(format!(
+            "A message of {} bytes is too large to replicate",
+            bytes.len()
+        )));
+    }
+    socket
+        .write_all(&(bytes.len() as u64).to_be_bytes())
+        .await?;
+    socket.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// Returns `None` once the leader has closed the connection.
+async fn receive<T: DeserializeOwned>(socket: &mut TcpStream) -> Result<Option<Message<T>>> {
+    let mut length = [0; 8];
+    match socket.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = u64::from_be_bytes(length);
+    if length > MAX_MESSAGE_SIZE {
+        return Err(Error::Corruption(format!(
+            "Leader sent a frame of {} bytes",
+            length
+        )));
+    }
+    let mut bytes = vec![0; length as usize];
+    socket.read_exact(&mut bytes).await?;
+    Ok(Some(bincode::deserialize(&bytes)?))
+}
+
+/// Sends the writes made to the tree to every follower that connects to the
+/// listener, until accepting a connection fails. Writes made with
+/// `Durability::None` are not sent.
+///
+/// Tables are not replicated, so once the tree is served, any table waiting
+/// to be ingested and any later call to `ingest_sstable` fails with
+/// `Error::InvalidArgument`.
+pub async fn serve<T>(client: Arc<LSMTreeClient<T>>, listener: TcpListener) -> Result<()>
+where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync + Debug + 'static,
+{
+    client.tree.lead().await;
+    loop {
+        let (socket, address) = listener.accept().await?;
+        info!("Follower connected from {}", address);
+        let client = client.clone();
+        spawn(async move {
+            if let Err(e) = lead(&client, socket).await {
+                warn!("Stopped replicating to {}: {}", address, e);
+            }
+        });
+    }
+}
+
+/// Sends a follower the batches after the sequence it asks for, preceded by
+/// the leader's tables if some of them are no longer in a WAL, or if the
+/// follower is missing an ingested table.
+async fn lead<T>(client: &LSMTreeClient<T>, mut socket: TcpStream) -> Result<()>
+where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync + Debug + 'static,
+{
+    let mut from = [0; 8];
+    socket.read_exact(&mut from).await?;
+    let from = u64::from_be_bytes(from);
+
+    let tree = &client.tree;
+    let (tables, changes) = {
+        let lock = tree.buffers.read().await;
+        let retained = tree.retained(&lock);
+        let tables = tree.snapshot_of(&lock).tables();
+        // Ingested tables are not among the changes, so a follower that is
+        // missing one must be sent every table.
+        let ingested = tables
+            .iter()
+            .any(|t| matches!(t.sequence(), Some(x) if x > from));
+        if from < retained || ingested {
+            (Some((retained, tables)), tree.changes_of(&lock, retained)?)
+        } else {
+            (None, tree.changes_of(&lock, from)?)
+        }
+    };
+    if let Some((sequence, tables)) = tables {
+        info!(
+            "Sending {} tables up to sequence {}",
+            tables.len(),
+            sequence
+        );
+        send::<T>(
+            &mut socket,
+            &Message::CatchUp {
+                sequence,
+                tables: tables.iter().map(|t| t.sequence()).collect(),
+            },
+        )
+        .await?;
+        for table in tables {
+            send_table(&mut socket, &table).await?;
+        }
+    }
+
+    let mut changes = Box::pin(changes);
+    while let Some(batch) = changes.next().await {
+        send_batch(&mut socket, &batch?).await?;
+    }
+    Ok(())
+}
+
+/// Sends a batch as a single message if it fits, and in chunks otherwise.
+async fn send_batch<T: Serialize + Clone>(
+    socket: &mut TcpStream,
+    batch: &[Entry<T>],
+) -> Result<()> {
+    let message = Message::Batch(batch.to_vec());
+    if bincode::serialized_size(&message)? <= MAX_MESSAGE_SIZE {
+        return send(socket, &message).await;
+    }
+    let bytes = bincode::serialize(batch)?;
+    if bytes.len() > MAX_BATCH_SIZE {
+        return Err(Error::InvalidArgument(format!(
+            "A batch of {} bytes is too large to replicate",
+            bytes.len()
+        )));
+    }
+    for chunk in bytes.chunks(CHUNK_SIZE) {
+        send::<T>(socket, &Message::Chunk(chunk.to_vec())).await?;
+    }
+    send::<T>(socket, &Message::EndOfBatch).await
+}
+
+async fn send_table<T: Serialize>(socket: &mut TcpStream, table: &SSTable<T>) -> Result<()> {
+    let path = table.path().ok_or_else(|| {
+        Error::InvalidArgument(format!(
+            "Table {} uses a legacy layout, and cannot be replicated",
+            table.id()
+        ))
+    })?;
+    let mut file = File::open(path).await?;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        send::<T>(socket, &Message::Chunk(chunk[..read].to_vec())).await?;
+    }
+    send::<T>(socket, &Message::EndOfTable).await
+}
+
+/// Connects to a leader and applies the writes made to it, until the leader
+/// closes the connection. Writes already applied are not sent again, so the
+/// follower may reconnect after an error.
+///
+/// A follower that has fallen too far behind is sent the leader's tables,
+/// which it can only load if it holds no data; otherwise it must be
+/// recreated. From the first call on, any other write to the follower, or
+/// table ingested by it, fails with `Error::InvalidArgument`.
+pub async fn follow<T>(client: &LSMTreeClient<T>, leader: impl ToSocketAddrs) -> Result<()>
+where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync + Debug + 'static,
+{
+    client.tree.follow().await;
+    let mut socket = TcpStream::connect(leader).await?;
+    let sequence = client.tree.sequence.load(Ordering::SeqCst);
+    socket.write_all(&sequence.to_be_bytes()).await?;
+    info!("Following leader from sequence {}", sequence);
+    // The chunks received so far of a batch too large for a single message.
+    let mut chunks = Vec::new();
+    while let Some(message) = receive::<T>(&mut socket).await? {
+        match message {
+            Message::CatchUp { sequence, tables } => {
+                catch_up(client, &mut socket, sequence, tables).await?
+            }
+            Message::Batch(batch) => client.apply(batch).await?,
+            Message::Chunk(bytes) => {
+                if chunks.len() + bytes.len() > MAX_BATCH_SIZE {
+                    return Err(Error::Corruption(format!(
+                        "Leader sent a batch of more than {} bytes",
+                        MAX_BATCH_SIZE
+                    )));
+                }
+                chunks.extend(bytes)
+            }
+            Message::EndOfBatch => {
+                let batch = bincode::deserialize(&std::mem::take(&mut chunks))?;
+                client.apply(batch).await?
+            }
+            Message::EndOfTable => {
+                return Err(Error::Corruption(
+                    "Received a table without a catch up".to_string(),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Installs the leader's tables, with the sequences they have there, then
+/// advances the sequence to the newest write they hold.
+async fn catch_up<T>(
+    client: &LSMTreeClient<T>,
+    socket: &mut TcpStream,
+    sequence: u64,
+    tables: Vec<Option<u64>>,
+) -> Result<()>
+where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync + Debug + 'static,
+{
+    let tree = &client.tree;
+    if tree.sequence.load(Ordering::SeqCst) != 0 || tree.first.load().is_some() {
+        return Err(Error::InvalidArgument(
+            "The follower is too far behind the leader, and must be recreated".to_string(),
+        ));
+    }
+    info!(
+        "Catching up to sequence {} from {} tables",
+        sequence,
+        tables.len()
+    );
+    for ingested in tables {
+        // Files left behind by a crash are removed when the tree is loaded.
+        let path = tree.dir.join(Key::new().hex()).with_extension("replica");
+        let mut file = File::create(&path).await?;
+        loop {
+            match receive::<T>(socket).await? {
+                Some(Message::Chunk(bytes)) => file.write_all(&bytes).await?,
+                Some(Message::EndOfTable) => break,
+                _ => {
+                    return Err(Error::Corruption(
+                        "The leader did not finish sending a table".to_string(),
+                    ))
+                }
+            }
+        }
+        file.sync_all().await?;
+        drop(file);
+        let result = client.install_sstable(&path, ingested).await;
+        remove_file(&path).await?;
+        result?;
+    }
+    tree.sequence.fetch_max(sequence, Ordering::SeqCst);
+    tree.state().await.save(&tree.dir).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use rocket::tokio::{
+        self, fs::remove_file, net::TcpListener, spawn, task::JoinHandle, time::sleep,
+    };
+
+    use crate::{
+        core::key::Key,
+        lsm_trees::client::LSMTreeClient,
+        sstables::{block::Compression, sstable_writer::SSTableWriter},
+        testing::{options, TempDir},
+        Error, Result,
+    };
+
+    use super::{follow, serve, CHUNK_SIZE};
+
+    /// Serves the leader, and has the follower follow it.
+    async fn replicate(
+        leader: &Arc<LSMTreeClient<String>>,
+        follower: &Arc<LSMTreeClient<String>>,
+    ) -> (JoinHandle<Result<()>>, JoinHandle<Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = spawn(serve(leader.clone(), listener));
+        let follower = follower.clone();
+        let following = spawn(async move { follow(&follower, address).await });
+        (server, following)
+    }
+
+    /// Waits until the follower holds the key.
+    async fn wait_for(follower: &LSMTreeClient<String>, key: &Key) {
+        for _ in 0..500 {
+            if follower.read(key).await.unwrap().is_some() {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replication() {
+        let dir = TempDir::new();
+        let (leader_dir, follower_dir) = (dir.join("leader"), dir.join("follower"));
+        let leader = Arc::new(
+            LSMTreeClient::<String>::new(leader_dir.clone(), options())
+                .await
+                .unwrap(),
+        );
+        let keys: Vec<_> = (0..200).map(|_| Key::new()).collect();
+        for (i, k) in keys.iter().enumerate().take(100) {
+            leader
+                .write(k.clone(), Some(format!("value{}", i)))
+                .await
+                .unwrap();
+        }
+        leader.write(keys[0].clone(), None).await.unwrap();
+
+        let follower = Arc::new(
+            LSMTreeClient::<String>::new(follower_dir.clone(), options())
+                .await
+                .unwrap(),
+        );
+        let (server, following) = replicate(&leader, &follower).await;
+        for (i, k) in keys.iter().enumerate().skip(100) {
+            leader
+                .write(k.clone(), Some(format!("value{}", i)))
+                .await
+                .unwrap();
+        }
+
+        let last = keys.last().unwrap();
+        wait_for(&follower, last).await;
+        assert_eq!(follower.read(&keys[0]).await.unwrap(), None);
+        for (i, k) in keys.iter().enumerate().skip(1) {
+            assert_eq!(follower.read(k).await.unwrap(), Some(format!("value{}", i)));
+        }
+        assert_eq!(
+            follower.read_with_version(last).await.unwrap(),
+            leader.read_with_version(last).await.unwrap()
+        );
+
+        // Only the leader's writes are applied.
+        let key = Key::new();
+        let value = "value".to_string();
+        for result in [
+            follower.write(key.clone(), None).await,
+            follower.write_batch(vec![(key.clone(), None)]).await,
+            follower
+                .put_if_absent(key.clone(), value.clone())
+                .await
+                .map(|_| ()),
+            follower
+                .transaction(|tx| async move {
+                    tx.put(key, value);
+                    Ok(())
+                })
+                .await,
+        ] {
+            assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        }
+
+        following.abort();
+        server.abort();
+        for client in [leader, follower] {
+            client.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_batch() {
+        let dir = TempDir::new();
+        let leader = Arc::new(
+            LSMTreeClient::<String>::new(dir.join("leader"), options())
+                .await
+                .unwrap(),
+        );
+        let follower = Arc::new(
+            LSMTreeClient::<String>::new(dir.join("follower"), options())
+                .await
+                .unwrap(),
+        );
+        let (server, following) = replicate(&leader, &follower).await;
+
+        let (large, after) = (Key::new(), Key::new());
+        let value = "x".repeat(2 * CHUNK_SIZE + 1);
+        leader
+            .write(large.clone(), Some(value.clone()))
+            .await
+            .unwrap();
+        leader
+            .write(after.clone(), Some("after".to_string()))
+            .await
+            .unwrap();
+
+        wait_for(&follower, &after).await;
+        assert_eq!(follower.read(&large).await.unwrap(), Some(value));
+        assert_eq!(
+            follower.read(&after).await.unwrap(),
+            Some("after".to_string())
+        );
+
+        following.abort();
+        server.abort();
+        for client in [leader, follower] {
+            client.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_catch_up() {
+        let dir = TempDir::new();
+        let leader = Arc::new(
+            LSMTreeClient::<String>::new(dir.join("leader"), options())
+                .await
+                .unwrap(),
+        );
+        let path = dir.join("table.sst");
+        let ingested = Key::new();
+        let mut writer = SSTableWriter::create(path.clone(), 1, Compression::None)
+            .await
+            .unwrap();
+        writer
+            .write(ingested.clone(), Some("ingested".to_string()))
+            .await
+            .unwrap();
+        writer.finish().await.unwrap();
+        leader.ingest_sstable(&path).await.unwrap();
+        let keys: Vec<_> = (0..50).map(|_| Key::new()).collect();
+        for (i, k) in keys.iter().enumerate() {
+            leader
+                .write(k.clone(), Some(format!("value{}", i)))
+                .await
+                .unwrap();
+        }
+        leader.wait_for_flush().await;
+
+        let follower = Arc::new(
+            LSMTreeClient::<String>::new(dir.join("follower"), options())
+                .await
+                .unwrap(),
+        );
+        let (server, following) = replicate(&leader, &follower).await;
+        wait_for(&follower, keys.last().unwrap()).await;
+        for k in keys.iter().chain([&ingested]) {
+            assert_eq!(
+                follower.read_with_version(k).await.unwrap(),
+                leader.read_with_version(k).await.unwrap()
+            );
+        }
+
+        following.abort();
+        server.abort();
+        for client in [leader, follower] {
+            client.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ingest() {
+        let dir = TempDir::new();
+        let leader = Arc::new(
+            LSMTreeClient::<String>::new(dir.join("leader"), options())
+                .await
+                .unwrap(),
+        );
+        let follower = Arc::new(
+            LSMTreeClient::<String>::new(dir.join("follower"), options())
+                .await
+                .unwrap(),
+        );
+        let (server, following) = replicate(&leader, &follower).await;
+        let key = Key::new();
+        leader
+            .write(key.clone(), Some("value".to_string()))
+            .await
+            .unwrap();
+        wait_for(&follower, &key).await;
+
+        let path = dir.join("table.sst");
+        let ingested = Key::new();
+        let mut writer = SSTableWriter::create(path.clone(), 1, Compression::None)
+            .await
+            .unwrap();
+        writer
+            .write(ingested.clone(), Some("ingested".to_string()))
+            .await
+            .unwrap();
+        writer.finish().await.unwrap();
+        for client in [&leader, &follower] {
+            assert!(matches!(
+                client.ingest_sstable(&path).await,
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+        remove_file(&path).await.unwrap();
+        assert_eq!(leader.read(&ingested).await.unwrap(), None);
+
+        // Writes are still replicated afterwards.
+        let after = Key::new();
+        leader
+            .write(after.clone(), Some("after".to_string()))
+            .await
+            .unwrap();
+        wait_for(&follower, &after).await;
+        assert_eq!(
+            follower.read(&after).await.unwrap(),
+            Some("after".to_string())
+        );
+
+        following.abort();
+        server.abort();
+        for client in [leader, follower] {
+            client.shutdown().await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use rocket::{
+    serde::{DeserializeOwned, Serialize},
+    tokio::fs::{read_dir, remove_file},
+};
+
+use crate::{
+    core::{entry::Entry, key::Key},
+    persistance::wal::{Timed, WAL},
+    sstables::write_buffer::WriteBuffer,
+    Error, Result,
+};
+
+use super::state::State;
+
+/// The point in the history of a tree to restore it to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryTarget {
+    /// Every write up to and including the sequence number.
+    Sequence(u64),
+    /// Every write logged at or before the time. Batches logged by versions
+    /// that did not record the time are only restored along with a later
+    /// batch that was logged in time.
+    Time(SystemTime),
+}
+
+/// The batches of a single WAL, each with the time it was logged if known.
+struct Log<T> {
+    batches: Vec<Timed<Vec<Entry<T>>>>,
+}
+
+impl<T> Log<T> {
+    fn first(&self) -> Option<u64> {
+        self.batches.first().map(|x| x.0[0].sequence)
+    }
+
+    fn last(&self) -> Option<u64> {
+        self.batches.last().map(|x| x.0[0].sequence)
+    }
+}
+
+/// Reads a log without changing it, as it may belong to the archive. A torn
+/// record at its end is left out.
+async fn read_log<T: Serialize + DeserializeOwned + Clone>(path: PathBuf) -> Result<Log<T>> {
+    let (batches, _) = WriteBuffer::<T>::read_wal(path).await?;
+    Ok(Log {
+        batches: batches.into_iter().filter(|x| !x.0.is_empty()).collect(),
+    })
+}
+
+/// Returns the ranges of sequences after `retained` and before the last batch
+/// that no batch holds. Ingested tables and writes made with
+/// `Durability::None` take sequences without being logged, so only the ranges
+/// that are not wholly taken by the ingested tables are returned.
+fn gaps<U>(
+    batches: &BTreeMap<u64, U>,
+    retained: u64,
+    ingested: &[(String, u64)],
+) -> Vec<(u64, u64)> {
+    let mut gaps = Vec::new();
+    let mut next = retained + 1;
+    for &sequence in batches.keys() {
+        if sequence > next && (next..sequence).any(|x| !ingested.iter().any(|y| y.1 == x)) {
+            gaps.push((next, sequence - 1));
+        }
+        next = sequence + 1;
+    }
+    gaps
+}
+
+/// Rolls a copy of a tree, such as one made by `LSMTreeClient::checkpoint`,
+/// forward to the target by replaying the WALs in `archive` (see
+/// `LSMTreeOptions::wal_archive`). Writes made after the target, including
+/// any held in the copy's own WALs, are discarded. Writes made with
+/// `Durability::None` were never logged, and cannot be restored. Any other
+/// sequence missing from the archive, such as one held by a WAL that was
+/// removed from it, is logged as a warning, as its writes are lost.
+///
+/// The tree must not be open. Returns the sequence of the newest write that
+/// was restored.
+pub async fn restore<T: Serialize + DeserializeOwned + Clone>(
+    dir: &Path,
+    archive: &Path,
+    target: RecoveryTarget,
+) -> Result<u64> {
+    let state = State::load(dir).await?;
+    let wals_dir = dir.join(&state.layout.wals);
+    let own: Vec<_> = state
+        .builders
+        .iter()
+        .chain([&state.wal])
+        .map(|id| wals_dir.join(id).with_extension("wal"))
+        .collect();
+
+    let mut logs = Vec::new();
+    for path in own.iter() {
+        logs.push(read_log::<T>(path.clone()).await?);
+    }
+    // Writes older than the copy's own WALs have been merged into its tables.
+    let retained = match logs.iter().filter_map(|x| x.first()).min() {
+        Some(x) => x - 1,
+        None => state.sequence,
+    };
+    let mut files = read_dir(archive).await?;
+    while let Some(x) = files.next_entry().await? {
+        if x.path().extension().is_some_and(|x| x == "wal") {
+            logs.push(read_log::<T>(x.path()).await?);
+        }
+    }
+    logs.retain(|x| matches!(x.last(), Some(last) if last > retained));
+    logs.sort_by_key(|x| x.first());
+
+    let target = match target {
+        RecoveryTarget::Sequence(x) if x < retained => {
+            return Err(Error::InvalidArgument(format!(
+                "Writes up to sequence {} have been merged into tables, and \
+                 cannot be undone",
+                retained
+            )))
+        }
+        RecoveryTarget::Sequence(x) => x,
+        RecoveryTarget::Time(time) => {
+            let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let time = time.as_millis() as u64;
+            logs.iter()
+                .flat_map(|x| x.batches.iter())
+                .filter(|x| x.1.is_some_and(|logged| logged <= time))
+                .map(|x| x.0[0].sequence)
+                .fold(retained, u64::max)
+        }
+    };
+
+    // A write may be held both by the copy and by the archive.
+    let mut batches = BTreeMap::new();
+    for (batch, time) in logs.into_iter().flat_map(|x| x.batches) {
+        let sequence = batch[0].sequence;
+        if sequence > retained && sequence <= target {
+            batches.insert(sequence, (batch, time));
+        }
+    }
+    let last = batches.keys().next_back().copied().unwrap_or(retained);
+    for (first, last) in gaps(&batches, retained, &state.ingested) {
+        warn!(
+            "Sequences {} to {} are missing from the archive, and their writes \
+             cannot be restored",
+            first, last
+        );
+    }
+    info!(
+        "Restoring {} batches up to sequence {}",
+        batches.len(),
+        last
+    );
+
+    let wal = Key::new().hex();
+    let mut log = WAL::create(wals_dir.join(&wal).with_extension("wal")).await?;
+    log.write_all_at(batches.values().map(|(x, time)| (x, *time)), true)
+        .await?;
+    log.close().await?;
+    // Ingested tables cannot be undone, so later writes must still be newer.
+    let sequence = state.ingested.iter().map(|x| x.1).fold(last, u64::max);
+    State::new(
+        wal,
+        Vec::new(),
+        state.runs,
+        state.layout,
+        sequence,
+        state.ingested,
+    )
+    .save(dir)
+    .await?;
+    // These hold the writes after the target, which must not be archived
+    // again once the tree is opened.
+    for path in own {
+        remove_file(path).await?;
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    use rocket::tokio::{
+        self,
+        fs::{read, read_dir},
+        time::sleep,
+    };
+
+    use crate::{
+        core::key::Key,
+        lsm_trees::{client::LSMTreeClient, options::LSMTreeOptions},
+        sstables::write_buffer::WriteBuffer,
+        testing::{options, TempDir},
+        Error,
+    };
+
+    use super::{gaps, restore, RecoveryTarget};
+
+    /// Waits until a WAL in the archive holds the write.
+    async fn wait_for_archive(archive: &Path, sequence: u64) {
+        for _ in 0..500 {
+            if let Ok(mut files) = read_dir(archive).await {
+                while let Some(x) = files.next_entry().await.unwrap() {
+                    let (batches, _) = WriteBuffer::<String>::read_wal(x.path()).await.unwrap();
+                    if batches.iter().any(|x| x.0[0].sequence == sequence) {
+                        return;
+                    }
+                }
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Write {} was not archived", sequence);
+    }
+
+    async fn contents(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+        let mut contents = BTreeMap::new();
+        let mut files = read_dir(dir).await.unwrap();
+        while let Some(x) = files.next_entry().await.unwrap() {
+            let name = x.file_name().to_string_lossy().to_string();
+            contents.insert(name, read(x.path()).await.unwrap());
+        }
+        contents
+    }
+
+    #[test]
+    fn test_gaps() {
+        let batches: BTreeMap<_, _> = [3, 4, 6, 9, 10].into_iter().map(|x| (x, ())).collect();
+        assert_eq!(gaps(&batches, 2, &[]), vec![(5, 5), (7, 8)]);
+        assert_eq!(
+            gaps(&batches, 0, &[("a".to_string(), 5)]),
+            vec![(1, 2), (7, 8)]
+        );
+        let ingested = [("a".to_string(), 7), ("b".to_string(), 8)];
+        assert_eq!(gaps(&batches, 2, &ingested), vec![(5, 5)]);
+    }
+
+    #[tokio::test]
+    async fn test_restore() {
+        let dir = TempDir::new();
+        let (backup, copy, archive) = (dir.join("backup"), dir.join("copy"), dir.join("archive"));
+        let client = LSMTreeClient::<String>::new(
+            dir.join("tree"),
+            LSMTreeOptions {
+                wal_archive: Some(archive.clone()),
+                ..options()
+            },
+        )
+        .await
+        .unwrap();
+        let keys: Vec<_> = (0..50).map(|_| Key::new()).collect();
+        for k in keys.iter() {
+            client
+                .write(k.clone(), Some("old".to_string()))
+                .await
+                .unwrap();
+        }
+        client.wait_for_flush().await;
+        client.checkpoint(&backup).await.unwrap();
+        client.checkpoint(&copy).await.unwrap();
+        for k in keys.iter() {
+            client
+                .write(k.clone(), Some("good".to_string()))
+                .await
+                .unwrap();
+        }
+        let (good, _) = client.read_with_version(&keys[49]).await.unwrap().unwrap();
+        sleep(Duration::from_millis(10)).await;
+        let time = SystemTime::now();
+        sleep(Duration::from_millis(10)).await;
+        // Enough bad writes that every good one is flushed and archived.
+        for _ in 0..4 {
+            for k in keys.iter() {
+                client
+                    .write(k.clone(), Some("bad".to_string()))
+                    .await
+                    .unwrap();
+            }
+        }
+        wait_for_archive(&archive, good).await;
+        client.shutdown().await.unwrap();
+        drop(client);
+
+        let archived = contents(&archive).await;
+        assert!(matches!(
+            restore::<String>(&backup, &archive, RecoveryTarget::Sequence(0)).await,
+            Err(Error::InvalidArgument(_))
+        ));
+        assert_eq!(
+            restore::<String>(&backup, &archive, RecoveryTarget::Sequence(good))
+                .await
+                .unwrap(),
+            good
+        );
+        assert_eq!(
+            restore::<String>(&copy, &archive, RecoveryTarget::Time(time))
+                .await
+                .unwrap(),
+            good
+        );
+        assert_eq!(contents(&archive).await, archived);
+
+        for path in [backup, copy] {
+            let client = LSMTreeClient::<String>::new(path, options()).await.unwrap();
+            for k in keys.iter() {
+                assert_eq!(client.read(k).await.unwrap(), Some("good".to_string()));
+            }
+            client.write(keys[0].clone(), None).await.unwrap();
+            assert_eq!(
+                client.read_with_version(&keys[1]).await.unwrap(),
+                Some((good - 48, "good".to_string()))
+            );
+            client.shutdown().await.unwrap();
+        }
+    }
+}
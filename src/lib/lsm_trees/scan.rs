@@ -0,0 +1,238 @@
+use std::{
+    collections::VecDeque,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use rocket::serde::DeserializeOwned;
+
+use crate::{
+    core::{entry::EntryData, key::Key},
+    Result,
+};
+
+use super::sstable_node::SSTableNode;
+
+/// Number of entries read from a table each time its buffer runs dry.
+const TABLE_BATCH: usize = 64;
+
+pub(super) enum Source<T> {
+    Memory(VecDeque<(Key, EntryData<T>)>),
+    Table {
+        node: Arc<Option<SSTableNode<T>>>,
+        /// The table of the run currently being read.
+        table: usize,
+        index: Option<u64>,
+        buffer: VecDeque<(Key, EntryData<T>)>,
+    },
+}
+
+impl<T: DeserializeOwned> Source<T> {
+    pub(super) fn memory(entries: Vec<(Key, EntryData<T>)>) -> Source<T> {
+        Source::Memory(entries.into())
+    }
+
+    pub(super) fn table(node: Arc<Option<SSTableNode<T>>>) -> Source<T> {
+        Source::Table {
+            node,
+            table: 0,
+            index: None,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    fn head(&self) -> Option<&Key> {
+        match self {
+            Source::Memory(entries) => entries.front(),
+            Source::Table { buffer, .. } => buffer.front(),
+        }
+        .map(|x| &x.0)
+    }
+
+    fn pop(&mut self) -> Option<(Key, EntryData<T>)> {
+        match self {
+            Source::Memory(entries) => entries.pop_front(),
+            Source::Table { buffer, .. } => buffer.pop_front(),
+        }
+    }
+
+    /// Ensures the head of the source is loaded, reading the next batch of
+    /// entries from disk if necessary.
+    async fn fill(&mut self, start: Bound<&Key>, end: Bound<&Key>) -> Result<()> {
+        let (node, table, index, buffer) = match self {
+            Source::Memory(_) => return Ok(()),
+            Source::Table {
+                node,
+                table,
+                index,
+                buffer,
+            } => (node, table, index, buffer),
+        };
+        let tables = match node.as_ref() {
+            Some(x) => x.tables(),
+            None => return Ok(()),
+        };
+        while buffer.is_empty() {
+            let mut reader = match tables.get(*table) {
+                Some(x) => x.reader().await?,
+                None => return Ok(()),
+            };
+            let mut i = match *index {
+                Some(i) => i,
+                None => reader.lower_bound(start).await?,
+            };
+            while buffer.len() < TABLE_BATCH {
+                match reader.read_index(i).await? {
+                    Some(x) if (Bound::Unbounded, end).contains(&x.key) => {
+                        buffer.push_back((x.key, x.data))
+                    }
+                    Some(_) => {
+                        // Later tables of the run lie entirely past the end.
+                        *table = tables.len();
+                        return Ok(());
+                    }
+                    None => {
+                        *table += 1;
+                        *index = None;
+                        break;
+                    }
+                }
+                i += 1;
+                *index = Some(i);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A k-way merge over a set of sorted sources, ordered from newest to oldest.
+pub(super) struct Scan<T> {
+    start: Bound<Key>,
+    end: Bound<Key>,
+    sources: Vec<Source<T>>,
+    /// Entries that had expired by this time are skipped.
+    time: u64,
+}
+
+impl<T: DeserializeOwned> Scan<T> {
+    pub(super) fn new(
+        start: Bound<Key>,
+        end: Bound<Key>,
+        sources: Vec<Source<T>>,
+        time: u64,
+    ) -> Scan<T> {
+        Scan {
+            start,
+            end,
+            sources,
+            time,
+        }
+    }
+
+    /// Returns the next live entry in key order. When several sources hold
+    /// the same key the newest wins, and deleted entries are skipped.
+    pub(super) async fn next(&mut self) -> Result<Option<(Key, T)>> {
+        loop {
+            for s in self.sources.iter_mut() {
+                s.fill(self.start.as_ref(), self.end.as_ref()).await?;
+            }
+            let newest = self
+                .sources
+                .iter()
+                .enumerate()
+                .filter_map(|(i, s)| s.head().map(|k| (i, k.clone())))
+                .min_by(|a, b| a.1.cmp(&b.1));
+            let (newest, key) = match newest {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+            let (_, data) = self.sources[newest].pop().unwrap();
+            for s in self.sources[newest + 1..].iter_mut() {
+                if s.head() == Some(&key) {
+                    s.pop();
+                }
+            }
+            if let Some(x) = data.into_data_at(self.time) {
+                return Ok(Some((key, x)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, ops::Bound, sync::Arc};
+
+    use arc_swap::ArcSwap;
+    use parking_lot::Mutex;
+    use rocket::tokio::{self, sync::broadcast::channel};
+
+    use crate::{
+        core::{
+            entry::{now, Entry, EntryData},
+            key::Key,
+        },
+        lsm_trees::sstable_node::SSTableNode,
+        sstables::{
+            block::Compression,
+            memtable::Sequence,
+            write_buffer::{Durability, WriteBuffer},
+        },
+        testing::TempDir,
+    };
+
+    use super::{Scan, Source};
+
+    #[tokio::test]
+    async fn test_scan() {
+        let dir = TempDir::new();
+        let mut keys: Vec<_> = (0..6).map(|_| Key::new()).collect();
+        keys.sort();
+
+        let wb = WriteBuffer::create(dir.join(""), Sequence::default(), channel(16).0)
+            .await
+            .unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            wb.write(
+                Entry::new(k.clone(), EntryData::Data(format!("old{}", i))),
+                Durability::Sync,
+            )
+            .await
+            .unwrap();
+        }
+        let b = wb.to_builder().await.unwrap();
+        let table = b.build(&dir.join(""), Compression::None).await.unwrap();
+        b.delete().await.unwrap();
+        let heap = Arc::new(Mutex::new(HashMap::new()));
+        let table = SSTableNode::register(table, &heap);
+        let node = SSTableNode::new(0, vec![table], ArcSwap::from_pointee(None));
+
+        let memory = vec![
+            (keys[1].clone(), EntryData::Deleted),
+            (keys[2].clone(), EntryData::Data("new2".to_string())),
+        ];
+        let mut scan = Scan::new(
+            Bound::Included(keys[1].clone()),
+            Bound::Excluded(keys[5].clone()),
+            vec![Source::memory(memory), Source::table(node.clone())],
+            now(),
+        );
+        let mut results = Vec::new();
+        while let Some(x) = scan.next().await.unwrap() {
+            results.push(x);
+        }
+        assert_eq!(
+            results,
+            vec![
+                (keys[2].clone(), "new2".to_string()),
+                (keys[3].clone(), "old3".to_string()),
+                (keys[4].clone(), "old4".to_string()),
+            ]
+        );
+
+        drop(scan);
+        drop(node);
+        let table = heap.lock().drain().next().unwrap().1;
+        Arc::try_unwrap(table).unwrap().delete().await.unwrap();
+    }
+}
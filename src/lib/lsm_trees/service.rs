@@ -1,53 +1,119 @@
-use std::{mem::replace, path::Path, sync::Arc, time::Duration};
+use std::{
+    mem::replace,
+    path::Path,
+    sync::{atomic::Ordering, Arc, Weak},
+};
 
 use arc_swap::ArcSwap;
-use log::{debug, trace};
+use log::{debug, error, trace};
 use rocket::{
     serde::{DeserializeOwned, Serialize},
-    tokio::time::sleep,
+    tokio::{
+        select,
+        sync::RwLockWriteGuard,
+        time::{interval, sleep, MissedTickBehavior},
+    },
 };
 
-use crate::sstables::{sstable_builder::SSTableBuilder, write_buffer::WriteBuffer};
+use crate::{
+    sstables::{memtable::Memtable, sstable_builder::SSTableBuilder, write_buffer::WriteBuffer},
+    Result,
+};
 
 use super::{
-    lsm_tree::{Heap, LSMTree},
+    leveled,
+    lsm_tree::{Buffers, Heap, Ingest, LSMTree},
+    options::{CompactionStrategy, LSMTreeOptions},
     sstable_node::SSTableNode,
+    state::State,
 };
 
 pub(super) struct LSMTreeService<T: Serialize + DeserializeOwned> {
     tree: Arc<LSMTree<T>>,
 }
 
-async fn merge_into_node<T: Serialize + DeserializeOwned>(
+/// Syncs writes made with `Durability::Buffered` every `sync_interval`. This
+/// runs apart from the service, so that a long merge or compaction cannot
+/// hold it off, until the tree is shut down or dropped. If a sync fails the
+/// tree is closed to further writes.
+pub(super) async fn sync_buffer<T: Serialize + DeserializeOwned + Clone>(tree: Weak<LSMTree<T>>) {
+    let mut ticks = match tree.upgrade() {
+        Some(x) => interval(x.options.sync_interval),
+        None => return,
+    };
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let tree = match tree.upgrade() {
+            Some(x) if !x.is_closed() => x,
+            _ => return,
+        };
+        let lock = tree.buffers.read().await;
+        if !lock.buffer.is_dirty() {
+            continue;
+        }
+        trace!("Syncing write buffer.");
+        if let Err(e) = lock.buffer.sync().await {
+            error!("Syncing the write buffer failed: {}", e);
+            drop(lock);
+            tree.close().await;
+            return;
+        }
+    }
+}
+
+async fn merge_into_node<T: Serialize + DeserializeOwned + Clone>(
     current: &ArcSwap<Option<SSTableNode<T>>>,
     heap: &Heap<T>,
     dir: &Path,
-) -> Arc<Option<SSTableNode<T>>> {
+    options: &LSMTreeOptions,
+) -> Result<Arc<Option<SSTableNode<T>>>> {
     loop {
         let first = current.load_full();
         let first = match first.as_ref() {
             Some(x) => x,
-            None => return first,
+            None => return Ok(first),
         };
 
         let second = first.next();
         let second = match second.as_ref() {
             Some(x) => {
-                if (3 * first.len()) / 4 <= x.len() {
-                    return second.clone();
+                if (first.len() as f64 * options.merge_ratio) as u64 <= x.len() {
+                    return Ok(second.clone());
                 }
                 x
             }
-            None => return second.clone(),
+            None => return Ok(second.clone()),
         };
-        let merged = SSTableBuilder::merge(first, second, dir).await;
+        let tables: Vec<_> = first
+            .tables()
+            .iter()
+            .chain(second.tables())
+            .map(|t| t.as_ref())
+            .collect();
+        // Nothing older than the second node can hold a deleted key.
+        let last = second.next().is_none();
+        let merged = SSTableBuilder::compact(&tables, dir, None, last, options.compression)
+            .await?
+            .pop()
+            .unwrap();
         debug!(
             "Merged {} into {} to form {}",
             first.id(),
             second.id(),
             merged.id()
         );
-        current.store(SSTableNode::new(merged, ArcSwap::from(second.next()), heap));
+        if merged.len() == 0 {
+            merged.delete().await?;
+            current.store(second.next());
+            continue;
+        }
+        let merged = SSTableNode::register(merged, heap);
+        current.store(SSTableNode::new(
+            0,
+            vec![merged],
+            ArcSwap::from(second.next()),
+        ));
     }
 }
 
@@ -62,110 +128,272 @@ impl<T: Serialize + DeserializeOwned + Clone> LSMTreeService<T> {
         }
     }
 
-    pub async fn run(mut self) {
+    /// Runs until the tree is shut down or dropped. If any step fails the
+    /// tree is closed to further writes, and the error is returned.
+    pub async fn run(mut self) -> Result<()> {
         loop {
             trace!("Service running...");
-            self = q!(self.check_deletion());
-            self.prune_dag().await;
-            if !self.merge().await && !self.new_buffer().await {
-                sleep(Duration::from_millis(1000)).await
+            if self.tree.is_closed() {
+                return self.close().await;
+            }
+            self = match self.check_deletion() {
+                Some(x) => x,
+                None => return Ok(()),
+            };
+            if let Err(e) = self.step().await {
+                error!("Background service failed: {}", e);
+                self.tree.close().await;
+                return Err(e);
             }
         }
     }
 
-    async fn prune_dag(&mut self) {
-        loop {
-            let garbage: Vec<_> = {
-                let mut nodes = self.tree.heap.lock();
-                let keys: Vec<_> = nodes
-                    .iter()
-                    .filter(|x| Arc::strong_count(x.1) == 1)
-                    .map(|x| x.0)
-                    .cloned()
-                    .collect();
-                keys.iter()
-                    .map(|x| {
-                        Arc::try_unwrap(nodes.remove(x).unwrap())
-                            .map_err(|_| ())
-                            .unwrap()
-                    })
-                    .collect()
-            };
-            if garbage.is_empty() {
-                break;
+    async fn step(&mut self) -> Result<()> {
+        self.prune_dag().await?;
+        if !self.ingest().await? && !self.merge().await? && !self.new_buffer().await? {
+            select! {
+                _ = sleep(self.tree.options.poll_interval) => {}
+                _ = self.tree.closing.notified() => {}
+                _ = self.tree.ingested.notified() => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Leaves the tree in a state that can be loaded again. Queued builders
+    /// are kept in their WALs, and are written out when the tree is next
+    /// opened, so writes made with `Durability::None` are lost.
+    async fn close(&mut self) -> Result<()> {
+        debug!("Service shutting down.");
+        self.prune_dag().await?;
+        self.tree.buffers.read().await.buffer.sync().await?;
+        self.save().await
+    }
+
+    async fn prune_dag(&mut self) -> Result<()> {
+        let garbage: Vec<_> = {
+            let mut tables = self.tree.heap.lock();
+            let keys: Vec<_> = tables
+                .iter()
+                .filter(|x| Arc::strong_count(x.1) == 1)
+                .map(|x| x.0)
+                .cloned()
+                .collect();
+            keys.iter()
+                .map(|x| {
+                    Arc::try_unwrap(tables.remove(x).unwrap())
+                        .map_err(|_| ())
+                        .unwrap()
+                })
+                .collect()
+        };
+        for x in garbage {
+            debug!("Deleting unused table: {}", x.id());
+            x.delete().await?;
+        }
+        Ok(())
+    }
+
+    /// Splices queued tables into the front of the tree, in the order they
+    /// were queued. A table can only be spliced once no buffered write that
+    /// came before it touches its range of keys, as those writes would
+    /// otherwise hide it. Any such writes are flushed first. Each table is
+    /// given the next sequence when it is first considered, so that its
+    /// entries are newer than the writes they hide, unless it came from a
+    /// leader and keeps the sequences it was given there. Writes made after
+    /// that are newer than the table, so they need not be flushed before it.
+    /// Tables that can no longer be ingested, as the tree now has followers
+    /// or follows a leader, are rejected instead.
+    async fn ingest(&mut self) -> Result<bool> {
+        let mut pending = std::mem::take(&mut *self.tree.ingests.lock());
+        if pending.is_empty() {
+            return Ok(false);
+        }
+        let mut ingested = Vec::new();
+        let rejected: Vec<_>;
+        let mut swap = false;
+        {
+            let lock = self.tree.buffers.write().await;
+            (pending, rejected) = pending
+                .into_iter()
+                .partition(|x| self.tree.check_ingest(x.replica).is_ok());
+            while let Some(Ingest { table, replica, .. }) = pending.first_mut() {
+                if !*replica && table.sequence().is_none() {
+                    // No write can be in progress while the buffers are
+                    // locked, so the table can take the next sequence. Any
+                    // write made after this is newer than the table and may
+                    // hide it, so steady writes cannot hold it back.
+                    let sequence = self.tree.sequence.fetch_add(1, Ordering::SeqCst) + 1;
+                    table.set_sequence(sequence);
+                }
+                // Only writes older than the table must be flushed before it,
+                // which for a replica without a sequence is every write.
+                let before = table.sequence().map_or(u64::MAX, |x| x.saturating_sub(1));
+                let (first, last) = table.range().unwrap();
+                let range = first.clone()..=last.clone();
+                let older = |m: Memtable<T>| !m.range(&range, before).is_empty();
+                if lock.builders.iter().any(|b| older(b.memtable())) {
+                    break;
+                }
+                if older(lock.buffer.memtable()) {
+                    swap = true;
+                    break;
+                }
+                let Ingest { table, sender, .. } = pending.remove(0);
+                debug!(
+                    "Ingesting table {} at sequence {:?}.",
+                    table.id(),
+                    table.sequence()
+                );
+                let table = SSTableNode::register(table, &self.tree.heap);
+                let first = self.tree.first.load_full();
+                self.tree
+                    .first
+                    .store(SSTableNode::new(0, vec![table], ArcSwap::from(first)));
+                ingested.push(sender);
             }
-            for x in garbage.into_iter().flatten() {
-                debug!("Deleting unused table: {}", x.id());
-                x.delete().await;
+            if swap {
+                // The buffer is swapped before the lock is released, so it
+                // holds no write made after a sequence was taken above.
+                let buffer = self.create_buffer().await?;
+                self.replace_buffer(lock, buffer).await?;
             }
         }
+        {
+            // Tables queued meanwhile must wait behind the ones left over.
+            let mut queue = self.tree.ingests.lock();
+            pending.append(&mut queue);
+            *queue = pending;
+        }
+        for ingest in rejected {
+            let _ = ingest.sender.send(self.tree.check_ingest(ingest.replica));
+            ingest.table.delete().await?;
+        }
+        if ingested.is_empty() {
+            return Ok(swap);
+        }
+        let result = self.save().await;
+        for sender in ingested {
+            let _ = sender.send(result.clone());
+        }
+        result?;
+        self.compact().await?;
+        Ok(true)
     }
 
-    async fn new_buffer(&mut self) -> bool {
-        let dir = self.tree.dir.clone();
+    async fn new_buffer(&mut self) -> Result<bool> {
         {
             let lock = self.tree.buffers.read().await;
-            if lock.buffer.size() <= 5 {
-                return false;
+            if lock.buffer.size_bytes() < self.tree.options.memtable_size
+                || lock.builders.len() >= self.tree.options.max_queued_builders
+            {
+                return Ok(false);
             }
         }
+        self.swap_buffer().await?;
+        Ok(true)
+    }
+
+    /// Replaces the write buffer with an empty one, queueing the old buffer
+    /// to be written to a table.
+    async fn swap_buffer(&mut self) -> Result<()> {
+        let new_wb = self.create_buffer().await?;
+        let lock = self.tree.buffers.write().await;
+        self.replace_buffer(lock, new_wb).await
+    }
+
+    async fn create_buffer(&self) -> Result<WriteBuffer<T>> {
+        WriteBuffer::create(
+            self.tree.wals_dir(),
+            self.tree.sequence.clone(),
+            self.tree.changes.clone(),
+        )
+        .await
+    }
+
+    /// Replaces the write buffer while `buffers` is locked, releasing the
+    /// lock once the new buffer has been saved.
+    async fn replace_buffer(
+        &self,
+        mut lock: RwLockWriteGuard<'_, Buffers<T>>,
+        new_wb: WriteBuffer<T>,
+    ) -> Result<()> {
         debug!("Swapping write buffer.");
-        let new_wb = WriteBuffer::create(dir.join("wals")).await;
-        {
-            let mut lock = self.tree.buffers.write().await;
-            let old_buffer = replace(&mut lock.buffer, new_wb);
-            let builder = old_buffer.to_builder().await;
-            lock.builders.push_front(builder);
-
-            // Downgrade lock to prevent blocking readers, but must not allow
-            // writes until pointer to new buffer is saved
-            let lock = lock.downgrade();
-            self.save().await;
-            drop(lock);
-        };
-        true
+        let old_buffer = replace(&mut lock.buffer, new_wb);
+        let builder = old_buffer.to_builder().await?;
+        lock.builders.push_front(builder);
+
+        // Downgrade lock to prevent blocking readers, but must not allow
+        // writes until pointer to new buffer is saved
+        let lock = lock.downgrade();
+        self.save_state(self.tree.state_of(&lock)).await
     }
 
-    async fn merge(&mut self) -> bool {
+    async fn merge(&mut self) -> Result<bool> {
         let tree = &self.tree;
 
         let builder = {
             let lock = tree.buffers.read().await;
             match lock.builders.back() {
                 Some(x) => x.clone(),
-                None => return false,
+                None => return Ok(false),
             }
         };
 
-        let table = builder.build(&tree.dir.join("tables")).await;
+        let table = builder
+            .build(&tree.tables_dir(), tree.options.compression)
+            .await?;
 
         {
             let mut lock = tree.buffers.write().await;
             let first = tree.first.load_full();
+            let table = SSTableNode::register(table, &tree.heap);
             tree.first
-                .store(SSTableNode::new(table, ArcSwap::from(first), &tree.heap));
+                .store(SSTableNode::new(0, vec![table], ArcSwap::from(first)));
             lock.builders.pop_back();
         }
+        tree.flushed.notify_waiters();
 
-        self.save().await;
-        builder.delete().await;
+        self.save().await?;
+        match &self.tree.options.wal_archive {
+            Some(dir) => builder.archive(dir).await?,
+            None => builder.delete().await?,
+        }
+        self.compact().await?;
+        Ok(true)
+    }
 
-        let mut current = merge_into_node(tree.first.as_ref(), &tree.heap, &tree.dir).await;
+    /// Merges tables according to the compaction strategy.
+    async fn compact(&mut self) -> Result<()> {
+        let tree = &self.tree;
+        let options = match &tree.options.compaction {
+            CompactionStrategy::Tiered => None,
+            CompactionStrategy::Leveled(options) => Some(options),
+        };
+        if let Some(options) = options {
+            leveled::compact(tree, options).await?;
+            return self.save().await;
+        }
+
+        let (dir, options) = (tree.tables_dir(), &tree.options);
+        let mut current = merge_into_node(tree.first.as_ref(), &tree.heap, &dir, options).await?;
         loop {
             match current.as_ref() {
                 Some(c) => {
-                    current = merge_into_node(c.next_lock(), &tree.heap, &tree.dir).await;
-                }
-                None => {
-                    self.save().await;
-                    return true;
+                    current = merge_into_node(c.next_lock(), &tree.heap, &dir, options).await?;
                 }
+                None => return self.save().await,
             };
         }
     }
 
-    async fn save(&self) {
-        let state = self.tree.state().await;
+    async fn save(&self) -> Result<()> {
+        self.save_state(self.tree.state().await).await
+    }
+
+    /// Saves a state built while `buffers` is locked, which must not be
+    /// locked again to build it.
+    async fn save_state(&self, state: State) -> Result<()> {
         debug!("State updated: {:?}", &state);
         state.save(&self.tree.dir).await
     }
@@ -0,0 +1,136 @@
+use std::{fmt::Debug, ops::RangeBounds, sync::Arc};
+
+use log::debug;
+use rocket::{
+    futures::{stream, Stream},
+    serde::{DeserializeOwned, Serialize},
+};
+
+use crate::{
+    core::{entry::now, key::Key},
+    sstables::{memtable::Memtable, sstable::SSTable},
+    Result,
+};
+
+use super::{
+    scan::{Scan, Source},
+    sstable_node::SSTableNode,
+};
+
+/// A consistent, read-only view of a tree at a single point in time.
+///
+/// The snapshot holds the in-memory buffers and the chain of tables as they
+/// were when it was taken. Entries written to the buffers later carry a
+/// newer sequence number and are ignored, and the tables it refers to are
+/// not deleted until it is dropped. Entries with a time-to-live are treated
+/// as expired if they had expired when the snapshot was taken.
+pub struct Snapshot<T> {
+    sequence: u64,
+    /// The time the snapshot was taken, in milliseconds since the Unix epoch.
+    time: u64,
+    memtables: Vec<Memtable<T>>,
+    first: Arc<Option<SSTableNode<T>>>,
+}
+
+impl<T> Snapshot<T> {
+    pub(super) fn new(
+        sequence: u64,
+        memtables: Vec<Memtable<T>>,
+        first: Arc<Option<SSTableNode<T>>>,
+    ) -> Snapshot<T> {
+        Snapshot {
+            sequence,
+            time: now(),
+            memtables,
+            first,
+        }
+    }
+
+    /// The sequence number of the last write visible to the snapshot.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Returns the tables visible to the snapshot, from the oldest run to
+    /// the newest.
+    pub(super) fn tables(&self) -> Vec<Arc<SSTable<T>>> {
+        let mut runs = Vec::new();
+        let mut current = self.first.clone();
+        while let Some(node) = current.as_ref() {
+            runs.push(node.tables().to_vec());
+            current = node.next();
+        }
+        runs.into_iter().rev().flatten().collect()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Clone + Debug> Snapshot<T> {
+    pub async fn read(&self, key: &Key) -> Result<Option<T>> {
+        Ok(self.read_with_version(key).await?.map(|x| x.1))
+    }
+
+    /// Returns the value of the key along with the sequence number of the
+    /// write that set it. Values written before sequence numbers were
+    /// recorded have version 0.
+    pub async fn read_with_version(&self, key: &Key) -> Result<Option<(u64, T)>> {
+        for m in self.memtables.iter() {
+            if let Some((sequence, data)) = m.get(key, self.sequence) {
+                debug!("Found {} in memory.", key.hex());
+                return Ok(data.into_data_at(self.time).map(|x| (sequence, x)));
+            }
+        }
+        let mut current = self.first.clone();
+        loop {
+            let c = match current.as_ref().as_ref() {
+                Some(c) => c,
+                None => {
+                    debug!("Did not find {}.", key.hex());
+                    return Ok(None);
+                }
+            };
+            let table = match c.table_for(key) {
+                Some(t) if t.may_contain(key) => t,
+                _ => {
+                    current = c.next();
+                    continue;
+                }
+            };
+            if let Some(x) = table.reader().await?.read_entry(key).await? {
+                let data = x.data.into_data_at(self.time);
+                debug!("Found {}={:?} in table {}.", key.hex(), &data, table.id());
+                break Ok(data.map(|d| (x.sequence, d)));
+            }
+            current = c.next()
+        }
+    }
+
+    /// Returns the live entries whose keys lie within the range, in key order.
+    /// The stream ends after the first error.
+    pub fn scan(&self, range: impl RangeBounds<Key>) -> impl Stream<Item = Result<(Key, T)>> {
+        let mut sources: Vec<_> = self
+            .memtables
+            .iter()
+            .map(|m| Source::memory(m.range(&range, self.sequence)))
+            .collect();
+        let mut current = self.first.clone();
+        while current.is_some() {
+            let next = current.as_ref().as_ref().unwrap().next();
+            sources.push(Source::table(current));
+            current = next;
+        }
+        let scan = Scan::new(
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+            sources,
+            self.time,
+        );
+        stream::unfold(Some(scan), |scan| async move {
+            let mut scan = scan?;
+            match scan.next().await {
+                Ok(Some(x)) => Some((Ok(x), Some(scan))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+}
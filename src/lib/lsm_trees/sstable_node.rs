@@ -1,51 +1,82 @@
-use std::{ops::Deref, sync::Arc};
+use std::sync::Arc;
 
 use arc_swap::ArcSwap;
 
-use crate::sstables::sstable::SSTable;
+use crate::{core::key::Key, sstables::sstable::SSTable};
 
 use super::lsm_tree::Heap;
 
+/// A sorted run of tables at some level of the tree. Every table in a run
+/// covers a distinct range of keys, and the tables are ordered by key.
 #[derive(Debug)]
 pub struct SSTableNode<T> {
-    table: SSTable<T>,
+    level: usize,
+    tables: Vec<Arc<SSTable<T>>>,
     next: NextSSTable<T>,
 }
 
 impl<T> SSTableNode<T> {
     pub fn new(
-        table: SSTable<T>,
+        level: usize,
+        tables: Vec<Arc<SSTable<T>>>,
         next: NextSSTable<T>,
-        heap: &Heap<T>,
     ) -> Arc<Option<SSTableNode<T>>> {
-        let id = table.id().to_string();
-        let node = Arc::new(Some(SSTableNode { table, next }));
-        heap.lock().insert(id, node.clone());
-        node
+        Arc::new(Some(SSTableNode {
+            level,
+            tables,
+            next,
+        }))
     }
 
-    pub fn table(&self) -> &SSTable<T> {
-        &self.table
+    /// Tracks a new table on the heap, so that it is deleted once it is no
+    /// longer part of any node.
+    pub fn register(table: SSTable<T>, heap: &Heap<T>) -> Arc<SSTable<T>> {
+        let table = Arc::new(table);
+        heap.lock().insert(table.id().to_string(), table.clone());
+        table
     }
 
-    pub fn next_lock(&self) -> &NextSSTable<T> {
-        &self.next
+    pub fn level(&self) -> usize {
+        self.level
     }
 
-    pub fn next(&self) -> Arc<Option<SSTableNode<T>>> {
-        self.next.load_full()
+    pub fn tables(&self) -> &[Arc<SSTable<T>>] {
+        &self.tables
     }
 
-    pub async fn delete(self) {
-        self.table.delete().await.unwrap();
+    /// Returns the only table in the run that could contain the key.
+    pub fn table_for(&self, key: &Key) -> Option<&Arc<SSTable<T>>> {
+        let i = self
+            .tables
+            .partition_point(|t| matches!(t.range(), Some((_, last)) if last < key));
+        let table = self.tables.get(i)?;
+        match table.range() {
+            Some((first, _)) if first <= key => Some(table),
+            _ => None,
+        }
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.tables.iter().map(|t| t.len()).sum()
+    }
+
+    /// Returns the total size of the run's tables in bytes.
+    pub fn size(&self) -> u64 {
+        self.tables.iter().map(|t| t.size()).sum()
+    }
+
+    pub fn id(&self) -> String {
+        let ids: Vec<_> = self.tables.iter().map(|t| t.id()).collect();
+        ids.join(",")
     }
-}
 
-impl<T> Deref for SSTableNode<T> {
-    type Target = SSTable<T>;
+    pub fn next_lock(&self) -> &NextSSTable<T> {
+        &self.next
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.table
+    pub fn next(&self) -> Arc<Option<SSTableNode<T>>> {
+        self.next.load_full()
     }
 }
 
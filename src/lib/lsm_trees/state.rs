@@ -5,40 +5,158 @@ use rocket::{
     tokio::fs::rename,
 };
 
-use crate::{core::key::Key, persistance::files::ImmutableFile};
+use crate::{core::key::Key, persistance::files::ImmutableFile, Error, Result};
+
+use super::options::DirectoryLayout;
+
+const MAGIC: [u8; 4] = *b"LKST";
+const VERSION: u32 = 1;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct State {
     pub wal: String,
     pub builders: Vec<String>,
+    /// The runs of the tree, from newest to oldest.
+    pub runs: Vec<Run>,
+    pub layout: DirectoryLayout,
+    /// The sequence number of the most recent write when the state was
+    /// saved. Every entry in the tables has a sequence at most this large.
+    pub sequence: u64,
+    /// The sequences given to ingested tables when they were spliced into
+    /// the tree, by table id. Their entries are read with these in place of
+    /// the ones they were written with.
+    pub ingested: Vec<(String, u64)>,
+}
+
+/// The tables of a single `SSTableNode`, in key order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub struct Run {
+    pub level: usize,
     pub tables: Vec<String>,
 }
 
+/// The state file written before it had a header, which always used the
+/// default directory layout.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct LegacyState {
+    wal: String,
+    builders: Vec<String>,
+    tables: Vec<String>,
+}
+
 impl State {
-    pub fn new(wal: String, builders: Vec<String>, tables: Vec<String>) -> State {
+    pub fn new(
+        wal: String,
+        builders: Vec<String>,
+        runs: Vec<Run>,
+        layout: DirectoryLayout,
+        sequence: u64,
+        ingested: Vec<(String, u64)>,
+    ) -> State {
         State {
             wal,
             builders,
-            tables,
+            runs,
+            layout,
+            sequence,
+            ingested,
+        }
+    }
+
+    /// Places each table of a legacy state file in its own level 0 run.
+    fn from_legacy(legacy: LegacyState) -> State {
+        let runs = legacy
+            .tables
+            .into_iter()
+            .map(|id| Run {
+                level: 0,
+                tables: vec![id],
+            })
+            .collect();
+        State::new(
+            legacy.wal,
+            legacy.builders,
+            runs,
+            DirectoryLayout::default(),
+            0,
+            Vec::new(),
+        )
+    }
+
+    pub async fn load(dir: &Path) -> Result<State> {
+        let file = ImmutableFile::from_existing(dir.join("state")).await?;
+        let mut reader = file.new_reader().await?;
+        let bytes = reader.read_all().await?;
+        State::from_bytes(&bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<State> {
+        if !bytes.starts_with(&MAGIC) {
+            return Ok(State::from_legacy(bincode::deserialize(bytes)?));
+        }
+        if bytes.len() < MAGIC.len() + 4 {
+            return Err(Error::Corruption("State file is truncated".to_string()));
+        }
+        let (version, body) = bytes[MAGIC.len()..].split_at(4);
+        let version = u32::from_be_bytes(version.try_into().unwrap());
+        if version != VERSION {
+            return Err(Error::Corruption(format!(
+                "Unsupported state version {}",
+                version
+            )));
         }
+        Ok(bincode::deserialize(body)?)
     }
 
-    pub async fn load(dir: &Path) -> State {
-        let file = ImmutableFile::from_existing(dir.join("state"))
-            .await
-            .unwrap();
-        let mut reader = file.new_reader().await.unwrap();
-        let bytes = reader.read_all().await.unwrap();
-        bincode::deserialize(&bytes).unwrap()
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok([
+            &MAGIC[..],
+            &VERSION.to_be_bytes(),
+            &bincode::serialize(self)?,
+        ]
+        .concat())
     }
 
-    pub async fn save(&self, dir: &Path) {
+    pub async fn save(&self, dir: &Path) -> Result<()> {
         let temp_path = dir.join(Key::new().hex()).with_extension("state");
-        let bytes = bincode::serialize(self).unwrap();
-        ImmutableFile::create(temp_path.clone(), &bytes)
-            .await
-            .unwrap();
-        rename(temp_path, dir.join("state")).await.unwrap();
+        ImmutableFile::create(temp_path.clone(), &self.to_bytes()?).await?;
+        rename(temp_path, dir.join("state")).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Run, State};
+
+    #[test]
+    fn test_migrate() {
+        let legacy = (
+            "wal".to_string(),
+            vec!["builder".to_string()],
+            vec!["a".to_string(), "b".to_string()],
+        );
+        let bytes = bincode::serialize(&legacy).unwrap();
+        let state = State::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            state.runs,
+            vec![
+                Run {
+                    level: 0,
+                    tables: vec!["a".to_string()]
+                },
+                Run {
+                    level: 0,
+                    tables: vec!["b".to_string()]
+                },
+            ]
+        );
+
+        let state = State::from_bytes(&state.to_bytes().unwrap()).unwrap();
+        assert_eq!(state.wal, "wal");
+        assert_eq!(state.runs.len(), 2);
     }
 }
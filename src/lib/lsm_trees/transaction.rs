@@ -0,0 +1,127 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    sync::Arc,
+};
+
+use log::debug;
+use parking_lot::Mutex;
+use rocket::serde::{DeserializeOwned, Serialize};
+
+use crate::{
+    core::{
+        entry::{Entry, EntryData},
+        key::Key,
+    },
+    Error, Result,
+};
+
+use super::{lsm_tree::LSMTree, snapshot::Snapshot};
+
+struct Changes<T> {
+    /// The version of each key read from the snapshot, or `None` if it was
+    /// absent.
+    reads: HashMap<Key, Option<u64>>,
+    writes: BTreeMap<Key, Option<T>>,
+}
+
+/// A set of reads and writes that are applied atomically.
+///
+/// Reads come from a snapshot taken when the transaction began, and writes
+/// are buffered until it commits. The commit fails with `Error::Conflict` if
+/// any key that was read has since been changed.
+pub struct Transaction<T: Serialize + DeserializeOwned> {
+    tree: Arc<LSMTree<T>>,
+    snapshot: Arc<Snapshot<T>>,
+    changes: Arc<Mutex<Changes<T>>>,
+}
+
+impl<T: Serialize + DeserializeOwned> Clone for Transaction<T> {
+    fn clone(&self) -> Self {
+        Transaction {
+            tree: self.tree.clone(),
+            snapshot: self.snapshot.clone(),
+            changes: self.changes.clone(),
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Clone + Debug> Transaction<T> {
+    pub(super) fn new(tree: Arc<LSMTree<T>>, snapshot: Snapshot<T>) -> Transaction<T> {
+        Transaction {
+            tree,
+            snapshot: Arc::new(snapshot),
+            changes: Arc::new(Mutex::new(Changes {
+                reads: HashMap::new(),
+                writes: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Reads the key, seeing any earlier writes made by the transaction.
+    pub async fn get(&self, key: &Key) -> Result<Option<T>> {
+        if let Some(x) = self.changes.lock().writes.get(key) {
+            return Ok(x.clone());
+        }
+        let value = self.snapshot.read_with_version(key).await?;
+        self.changes
+            .lock()
+            .reads
+            .entry(key.clone())
+            .or_insert(value.as_ref().map(|x| x.0));
+        Ok(value.map(|x| x.1))
+    }
+
+    pub fn put(&self, key: Key, value: T) {
+        self.changes.lock().writes.insert(key, Some(value));
+    }
+
+    pub fn delete(&self, key: Key) {
+        self.changes.lock().writes.insert(key, None);
+    }
+
+    /// Writes the changes as a single batch, provided that every key read
+    /// still has the version it had in the snapshot.
+    pub(super) async fn commit(self) -> Result<()> {
+        let (reads, writes) = {
+            let mut changes = self.changes.lock();
+            (
+                std::mem::take(&mut changes.reads),
+                std::mem::take(&mut changes.writes),
+            )
+        };
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let entries = writes
+            .into_iter()
+            .map(|(key, data)| {
+                Entry::new(key, data.map(EntryData::Data).unwrap_or(EntryData::Deleted))
+            })
+            .collect();
+
+        let tree = &self.tree;
+        tree.throttle().await?;
+        let lock = tree.buffers.read().await;
+        tree.check_writable()?;
+        let condition = || async {
+            let current = tree.snapshot_of(&lock);
+            for (key, version) in reads.iter() {
+                let now = current.read_with_version(key).await?.map(|x| x.0);
+                if now != *version {
+                    debug!("Transaction conflicts on {}.", key.hex());
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        };
+        match lock
+            .buffer
+            .write_batch_if(entries, tree.options.durability, condition)
+            .await?
+        {
+            true => Ok(()),
+            false => Err(Error::Conflict),
+        }
+    }
+}
@@ -1,5 +1,10 @@
 #[macro_use]
 pub mod core;
+pub mod error;
 pub mod lsm_trees;
 pub mod persistance;
 pub mod sstables;
+#[cfg(test)]
+mod testing;
+
+pub use error::{Error, Result};
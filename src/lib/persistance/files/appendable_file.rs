@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use rocket::tokio::{
@@ -15,25 +15,46 @@ pub struct AppendableFile {
 impl AppendableFile {
     pub async fn new(path: PathBuf) -> Result<AppendableFile> {
         let file = OpenOptions::new()
-            .write(true)
+            .append(true)
             .create(true)
             .open(&path)
             .await?;
         Ok(AppendableFile { file, path })
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub async fn size(&mut self) -> Result<u64> {
         Ok(self.file.metadata().await.map(|m| m.len())?)
     }
 
     pub async fn append(&mut self, buffer: &[u8]) -> Result<()> {
-        self.file.write_all(buffer).await?;
+        self.write(buffer).await?;
         self.file.sync_data().await?;
         Ok(())
     }
 
+    /// Appends the buffer without syncing it, so that it survives the process
+    /// exiting but may be lost if the machine crashes.
+    pub async fn write(&mut self, buffer: &[u8]) -> Result<()> {
+        self.file.write_all(buffer).await?;
+        self.file.flush().await?;
+        Ok(())
+    }
+
     pub async fn clear(&mut self) -> Result<()> {
-        self.file.set_len(0).await?;
+        self.truncate(0).await
+    }
+
+    pub async fn truncate(&mut self, size: u64) -> Result<()> {
+        self.file.set_len(size).await?;
+        self.file.sync_all().await?;
+        Ok(())
+    }
+
+    pub async fn sync(&mut self) -> Result<()> {
         self.file.sync_all().await?;
         Ok(())
     }
@@ -3,7 +3,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::{Error, Result};
+use anyhow::{bail, Error, Result};
 use rocket::tokio::{
     fs::{remove_file, File, OpenOptions},
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
@@ -38,7 +38,7 @@ impl ImmutableFile {
         Ok(ImmutableFile { path, size })
     }
 
-    pub async fn new_reader(self: &ImmutableFile) -> Result<FileReader> {
+    pub async fn new_reader(self: &ImmutableFile) -> Result<FileReader<'_>> {
         let file = OpenOptions::new().read(true).open(&self.path).await?;
         Ok(FileReader::new(file, self))
     }
@@ -53,7 +53,6 @@ impl ImmutableFile {
 }
 
 pub struct FileReader<'a> {
-    #[allow(dead_code)]
     owner: &'a ImmutableFile,
     file: File,
 }
@@ -75,6 +74,15 @@ impl<'a> FileReader<'a> {
     }
 
     pub async fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        // A corrupt length must not be allocated before the read fails.
+        if offset.checked_add(size).is_none_or(|end| end > self.size()) {
+            bail!(
+                "{} has no {} bytes at offset {}",
+                self.owner.path().display(),
+                size,
+                offset
+            );
+        }
         let mut buffer = vec![0; size.try_into().unwrap()];
         self.file.seek(SeekFrom::Start(offset)).await?;
         self.file.read_exact(&mut buffer).await?;
@@ -1,73 +1,458 @@
-use std::{marker::PhantomData, mem::size_of, path::PathBuf};
+use std::{
+    fmt::{self, Display},
+    marker::PhantomData,
+    mem::size_of,
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Ok, Result};
-use rocket::serde::{DeserializeOwned, Serialize};
+use anyhow::Result;
+use log::warn;
+use rocket::{
+    serde::{DeserializeOwned, Serialize},
+    tokio::fs::{copy, create_dir_all, remove_file, rename, File},
+};
+
+use crate::core::entry::now;
 
 use super::files::{AppendableFile, ImmutableFile};
 
+const MAGIC: [u8; 4] = *b"LWAL";
+/// Logs written before the format was versioned have no header, and their
+/// records have neither checksums nor times. They are rewritten when opened.
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = MAGIC.len() + size_of::<u32>();
+/// The length, a checksum of the length, so that a damaged length is not
+/// mistaken for a torn record, a checksum of the time and payload, and the
+/// time.
+const RECORD_HEADER_SIZE: usize = 2 * size_of::<u64>() + 2 * size_of::<u32>();
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WALError {
+    /// The file header names a format version that this build cannot read.
+    UnsupportedVersion { path: PathBuf, version: u32 },
+    /// A record before the end of the log failed its checksum or could not
+    /// be decoded, so the records after it cannot be trusted.
+    Corrupted { path: PathBuf, offset: u64 },
+}
+
+impl Display for WALError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WALError::UnsupportedVersion { path, version } => write!(
+                f,
+                "WAL {} has unsupported format version {}",
+                path.display(),
+                version
+            ),
+            WALError::Corrupted { path, offset } => {
+                write!(f, "WAL {} is corrupted at byte {}", path.display(), offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WALError {}
+
+/// A record along with the time it was written, in milliseconds since the
+/// Unix epoch, if that is known.
+pub type Timed<T> = (T, Option<u64>);
+
 #[derive(Debug)]
 pub struct WAL<T: Serialize + DeserializeOwned> {
     file: AppendableFile,
+    /// The length of the log at the end of the last complete write.
+    size: u64,
+    /// Set while a write is in progress. If it is still set when the next
+    /// write starts, the previous write failed or was cancelled part way
+    /// through, and whatever it left past `size` is truncated away.
+    writing: bool,
     log_type: PhantomData<T>,
 }
 
-fn read_entry<T: DeserializeOwned>(mut remaining: &[u8]) -> Option<(T, &[u8])> {
-    if remaining.len() < size_of::<u64>() {
-        return None;
+fn header() -> Vec<u8> {
+    [&MAGIC[..], &VERSION.to_be_bytes()].concat()
+}
+
+/// Encodes a record written at the time, in milliseconds since the Unix
+/// epoch, which is 0 if it is not known.
+fn encode_record(payload: &[u8], time: u64) -> Vec<u8> {
+    let size = (payload.len() as u64).to_be_bytes();
+    let time = time.to_be_bytes();
+    let mut checksum = crc32fast::Hasher::new();
+    checksum.update(&time);
+    checksum.update(payload);
+    [
+        &size[..],
+        &crc32fast::hash(&size).to_be_bytes(),
+        &checksum.finalize().to_be_bytes(),
+        &time,
+        payload,
+    ]
+    .concat()
+}
+
+/// The outcome of trying to read the record at the start of a buffer.
+enum Record<'a, T> {
+    /// The record, along with the time it was written if it is known.
+    Valid(T, Option<u64>, &'a [u8]),
+    /// The buffer ends part way through the record.
+    Incomplete,
+    /// The record is complete, but its checksum or contents are invalid.
+    Invalid(&'a [u8]),
+    /// The length of the record is damaged, so the end of the record, and
+    /// whether it was torn, cannot be known.
+    Damaged,
+}
+
+fn read_entry<T: DeserializeOwned>(remaining: &[u8]) -> Record<'_, T> {
+    if remaining.len() < RECORD_HEADER_SIZE {
+        return Record::Incomplete;
     }
-    let size_bytes: &[u8];
-    (size_bytes, remaining) = remaining.split_at(size_of::<u64>());
-    let size = u64::from_be_bytes(size_bytes.try_into().unwrap()) as usize;
+    let (header, remaining) = remaining.split_at(RECORD_HEADER_SIZE);
+    let (size_bytes, header) = header.split_at(size_of::<u64>());
+    let size = u64::from_be_bytes(size_bytes.try_into().unwrap());
+    let (size_checksum, header) = header.split_at(size_of::<u32>());
+    let (checksum, time) = header.split_at(size_of::<u32>());
+    let checksum = u32::from_be_bytes(checksum.try_into().unwrap());
 
-    if remaining.len() < size {
-        return None;
+    if crc32fast::hash(size_bytes) != u32::from_be_bytes(size_checksum.try_into().unwrap()) {
+        return Record::Damaged;
     }
-    let data: &[u8];
-    (data, remaining) = remaining.split_at(size);
+    if (remaining.len() as u64) < size {
+        return Record::Incomplete;
+    }
+    let (data, remaining) = remaining.split_at(size as usize);
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(time);
+    hasher.update(data);
+    if hasher.finalize() != checksum {
+        return Record::Invalid(remaining);
+    }
+    let time = Some(u64::from_be_bytes(time.try_into().unwrap())).filter(|x| *x != 0);
+    match bincode::deserialize(data) {
+        Ok(x) => Record::Valid(x, time, remaining),
+        Err(_) => Record::Invalid(remaining),
+    }
+}
 
-    Some((bincode::deserialize(data).ok()?, remaining))
+/// Reads the records of a log written before the format was versioned, which
+/// have no header and no checksums, returning them along with the length of
+/// the valid prefix of the file. A record cut short at the end of the file is
+/// taken to be torn. Without checksums, a file is otherwise only taken to be
+/// such a log if every byte of it is part of a record, and a record cut short
+/// before any complete one is only taken to be torn if the file could hold
+/// nothing but its length.
+fn read_legacy_entries<T: DeserializeOwned>(path: &Path, bytes: &[u8]) -> Result<(usize, Vec<T>)> {
+    let mut entries = Vec::new();
+    let mut remaining = bytes;
+    while !remaining.is_empty() {
+        let valid = bytes.len() - remaining.len();
+        let torn = || {
+            if entries.is_empty() && remaining.len() > size_of::<u64>() {
+                Err(WALError::Corrupted {
+                    path: path.to_owned(),
+                    offset: valid as u64,
+                })
+            } else {
+                Ok(valid)
+            }
+        };
+        if remaining.len() < size_of::<u64>() {
+            return Ok((torn()?, entries));
+        }
+        let (size_bytes, rest) = remaining.split_at(size_of::<u64>());
+        let size = u64::from_be_bytes(size_bytes.try_into().unwrap());
+        if (rest.len() as u64) < size {
+            return Ok((torn()?, entries));
+        }
+        let (data, rest) = rest.split_at(size as usize);
+        match bincode::deserialize(data) {
+            Ok(x) => entries.push(x),
+            Err(_) => {
+                return Err(WALError::Corrupted {
+                    path: path.to_owned(),
+                    offset: valid as u64,
+                }
+                .into())
+            }
+        }
+        remaining = rest;
+    }
+    Ok((bytes.len(), entries))
+}
+
+/// Reads the records of a versioned log and the times they were written,
+/// returning them along with the length of the valid prefix of the file.
+fn read_entries<T: DeserializeOwned>(path: &Path, bytes: &[u8]) -> Result<(usize, Vec<Timed<T>>)> {
+    let mut entries = Vec::new();
+    let mut remaining = &bytes[HEADER_SIZE..];
+    let mut valid = HEADER_SIZE;
+    loop {
+        match read_entry(remaining) {
+            Record::Valid(entry, time, r) => {
+                entries.push((entry, time));
+                remaining = r;
+                valid = bytes.len() - remaining.len();
+            }
+            Record::Damaged | Record::Invalid([_, ..]) => {
+                return Err(WALError::Corrupted {
+                    path: path.to_owned(),
+                    offset: valid as u64,
+                }
+                .into());
+            }
+            Record::Incomplete | Record::Invalid([]) => break,
+        }
+    }
+    Ok((valid, entries))
+}
+
+/// The records of a log file.
+struct Contents<T> {
+    entries: Vec<T>,
+    /// The time each record was written, where it is known.
+    times: Vec<Option<u64>>,
+    /// The length of the valid prefix of the file.
+    valid: usize,
+    /// Whether the file is in the current format, rather than one that must
+    /// be upgraded.
+    current: bool,
+}
+
+fn parse<T: DeserializeOwned, U: DeserializeOwned>(
+    path: &Path,
+    bytes: &[u8],
+    upgrade: impl FnMut(U) -> T,
+) -> Result<Contents<T>> {
+    let upgraded = |valid: usize, entries: Vec<U>| Contents {
+        times: vec![None; entries.len()],
+        entries: entries.into_iter().map(upgrade).collect(),
+        valid,
+        current: false,
+    };
+    // Left behind by a crash during `create`.
+    if bytes.len() < HEADER_SIZE && header().starts_with(bytes) {
+        return Ok(upgraded(bytes.len(), Vec::new()));
+    }
+    if bytes.len() < HEADER_SIZE || bytes[..MAGIC.len()] != MAGIC {
+        let (valid, entries) = read_legacy_entries(path, bytes)?;
+        return Ok(upgraded(valid, entries));
+    }
+    let version = u32::from_be_bytes(bytes[MAGIC.len()..HEADER_SIZE].try_into().unwrap());
+    if version != VERSION {
+        return Err(WALError::UnsupportedVersion {
+            path: path.to_owned(),
+            version,
+        }
+        .into());
+    }
+    let (valid, entries) = read_entries(path, bytes)?;
+    let (entries, times) = entries.into_iter().unzip();
+    Ok(Contents {
+        entries,
+        times,
+        valid,
+        current: true,
+    })
+}
+
+/// Moves a closed log into the directory, creating it if needed. Logs are
+/// copied instead when the directory is on another file system.
+pub async fn archive(path: &Path, dir: &Path) -> Result<()> {
+    create_dir_all(dir).await?;
+    let target = dir.join(path.file_name().unwrap());
+    if rename(path, &target).await.is_err() {
+        copy(path, &target).await?;
+        File::open(&target).await?.sync_all().await?;
+        remove_file(path).await?;
+    }
+    Ok(())
 }
 
 impl<T: Serialize + DeserializeOwned> WAL<T> {
     pub async fn write(&mut self, item: &T) -> Result<()> {
-        let bytes = bincode::serialize(&item)?;
-        self.file.append(&bytes.len().to_be_bytes()).await?;
-        self.file.append(&bytes).await?;
+        let bytes = encode_record(&bincode::serialize(&item)?, now());
+        self.append(&bytes, true).await
+    }
+
+    /// Appends the items as separate records, with a single sync if `sync` is
+    /// set. Unsynced records are made durable by a later call to `sync`.
+    pub async fn write_all<'a>(
+        &mut self,
+        items: impl IntoIterator<Item = &'a T>,
+        sync: bool,
+    ) -> Result<()>
+    where
+        T: 'a,
+    {
+        let time = now();
+        let mut bytes = Vec::new();
+        for item in items {
+            bytes.extend(encode_record(&bincode::serialize(item)?, time));
+        }
+        self.append(&bytes, sync).await
+    }
+
+    /// Appends the items like `write_all`, but records each as written at the
+    /// time given with it, as when they are copied from another log.
+    pub async fn write_all_at<'a>(
+        &mut self,
+        items: impl IntoIterator<Item = (&'a T, Option<u64>)>,
+        sync: bool,
+    ) -> Result<()>
+    where
+        T: 'a,
+    {
+        let mut bytes = Vec::new();
+        for (item, time) in items {
+            bytes.extend(encode_record(&bincode::serialize(item)?, time.unwrap_or(0)));
+        }
+        self.append(&bytes, sync).await
+    }
+
+    async fn append(&mut self, bytes: &[u8], sync: bool) -> Result<()> {
+        self.recover().await?;
+        self.writing = true;
+        if sync {
+            self.file.append(bytes).await?;
+        } else {
+            self.file.write(bytes).await?;
+        }
+        self.finish(bytes);
+        Ok(())
+    }
+
+    /// Truncates the records of a write that failed or was cancelled, which
+    /// were never acknowledged, so that later records follow the last
+    /// complete one.
+    async fn recover(&mut self) -> Result<()> {
+        if self.writing {
+            warn!(
+                "Truncating incomplete write at byte {} of {}",
+                self.size,
+                self.path().display()
+            );
+            self.file.truncate(self.size).await?;
+            self.writing = false;
+        }
         Ok(())
     }
 
-    async fn new(file: ImmutableFile) -> Result<(WAL<T>, Vec<T>)> {
-        let mut entries = Vec::new();
+    fn finish(&mut self, bytes: &[u8]) {
+        self.size += bytes.len() as u64;
+        self.writing = false;
+    }
 
+    /// Replays the records in the file. A torn record at the end of the file
+    /// is truncated away, but corruption anywhere else is reported as a
+    /// `WALError::Corrupted`. Records of unversioned logs are read as `U` and
+    /// converted with `upgrade`, and the log is rewritten in the current
+    /// format.
+    async fn new<U: DeserializeOwned>(
+        file: ImmutableFile,
+        upgrade: impl FnMut(U) -> T,
+    ) -> Result<(WAL<T>, Vec<T>)> {
         let mut reader = file.new_reader().await?;
         let bytes = reader.read_all().await?;
-        let mut remaining = bytes.as_slice();
+        let path = file.path().to_owned();
 
-        while let Some((entry, r)) = read_entry(remaining) {
-            entries.push(entry);
-            remaining = r;
+        let contents = parse(&path, &bytes, upgrade)?;
+        if contents.valid < bytes.len() {
+            warn!(
+                "Truncating torn record at byte {} of {}",
+                contents.valid,
+                path.display()
+            );
+        }
+        if !contents.current {
+            // The torn record is left out of the rewritten log.
+            return WAL::migrate(path, contents.entries).await;
+        }
+        let mut file = AppendableFile::new(path).await?;
+        if contents.valid < bytes.len() {
+            file.truncate(contents.valid as u64).await?;
         }
-
         let wal = WAL {
-            file: AppendableFile::new(file.path().to_owned()).await?,
-            log_type: PhantomData::default(),
+            file,
+            size: contents.valid as u64,
+            writing: false,
+            log_type: PhantomData,
         };
+        Ok((wal, contents.entries))
+    }
 
+    /// Reads the records in the file like `open_with`, but without changing
+    /// it. Returns each along with the time it was written, which is not
+    /// known for records of unversioned logs, and the length of the torn
+    /// record at the end of the file, if any, which opening the log would
+    /// truncate.
+    pub async fn read_with<U: DeserializeOwned>(
+        path: PathBuf,
+        upgrade: impl FnMut(U) -> T,
+    ) -> Result<(Vec<Timed<T>>, u64)> {
+        let file = ImmutableFile::from_existing(path).await?;
+        let bytes = file.new_reader().await?.read_all().await?;
+        let contents = parse(file.path(), &bytes, upgrade)?;
+        let entries = contents.entries.into_iter().zip(contents.times).collect();
+        Ok((entries, (bytes.len() - contents.valid) as u64))
+    }
+
+    /// Rewrites an unversioned log (or an empty file left behind by a crash
+    /// during `create`) in the current format.
+    async fn migrate(path: PathBuf, entries: Vec<T>) -> Result<(WAL<T>, Vec<T>)> {
+        if !entries.is_empty() {
+            warn!("Upgrading legacy WAL {}", path.display());
+        }
+        let mut contents = header();
+        // Unversioned logs do not record when their records were written.
+        for entry in entries.iter() {
+            contents.extend(encode_record(&bincode::serialize(entry)?, 0));
+        }
+        let temp_path = path.with_extension("upgrade");
+        ImmutableFile::create(temp_path.clone(), &contents).await?;
+        rename(&temp_path, &path).await?;
+        let wal = WAL {
+            file: AppendableFile::new(path).await?,
+            size: contents.len() as u64,
+            writing: false,
+            log_type: PhantomData,
+        };
         Ok((wal, entries))
     }
 
     pub async fn create(path: PathBuf) -> Result<WAL<T>> {
-        WAL::new(ImmutableFile::create(path, &[]).await.unwrap())
+        WAL::new(ImmutableFile::create(path, &header()).await?, |x: T| x)
             .await
             .map(|x| x.0)
     }
 
     pub async fn open(path: PathBuf) -> Result<(WAL<T>, Vec<T>)> {
-        WAL::new(ImmutableFile::from_existing(path.clone()).await.unwrap()).await
+        WAL::new(ImmutableFile::from_existing(path).await?, |x: T| x).await
+    }
+
+    /// Opens a log whose records may have been written before the format was
+    /// versioned, converting them with `upgrade`.
+    pub async fn open_with<U: DeserializeOwned>(
+        path: PathBuf,
+        upgrade: impl FnMut(U) -> T,
+    ) -> Result<(WAL<T>, Vec<T>)> {
+        WAL::new(ImmutableFile::from_existing(path).await?, upgrade).await
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
     }
 
     pub async fn clear(&mut self) -> Result<()> {
-        self.file.clear().await
+        self.file.truncate(HEADER_SIZE as u64).await?;
+        self.size = HEADER_SIZE as u64;
+        self.writing = false;
+        Ok(())
+    }
+
+    pub async fn sync(&mut self) -> Result<()> {
+        self.file.sync().await
     }
 
     pub async fn close(self) -> Result<PathBuf> {
@@ -84,9 +469,13 @@ impl<T: Serialize + DeserializeOwned> WAL<T> {
 mod test {
     use std::path::PathBuf;
 
-    use rocket::tokio;
+    use rocket::tokio::{self, fs::OpenOptions, io::AsyncWriteExt};
 
-    use crate::persistance::wal::WAL;
+    use crate::{
+        core::{entry::now, key::Key},
+        persistance::wal::{WALError, WAL},
+        testing::TempDir,
+    };
 
     #[tokio::test]
     pub async fn wal_test() {
@@ -103,4 +492,143 @@ mod test {
         w.delete().await.unwrap();
         assert_eq!(remaining, vec!["Hi!", "Hello there!", "Sup bro"]);
     }
+
+    #[tokio::test]
+    pub async fn time_test() {
+        let dir = TempDir::new();
+        let path = dir.join(format!("{}.wal", Key::new().hex()));
+        let before = now();
+        let mut wal = WAL::<String>::create(path.clone()).await.unwrap();
+        wal.write(&"Hi!".to_string()).await.unwrap();
+        wal.write(&"Hello there!".to_string()).await.unwrap();
+        wal.close().await.unwrap();
+        let after = now();
+
+        // Each record holds the time it was logged.
+        let (remaining, torn) = WAL::<String>::read_with(path, |x: String| x).await.unwrap();
+        assert_eq!(torn, 0);
+        assert_eq!(remaining.len(), 2);
+        for (_, time) in remaining {
+            assert!(time.is_some_and(|t| before <= t && t <= after));
+        }
+    }
+
+    #[tokio::test]
+    pub async fn corruption_test() {
+        let dir = TempDir::new();
+        let path = dir.join(format!("{}.wal", Key::new().hex()));
+        let mut wal = WAL::<String>::create(path.clone()).await.unwrap();
+        wal.write(&"Hi!".to_string()).await.unwrap();
+        wal.write(&"Hello there!".to_string()).await.unwrap();
+        wal.close().await.unwrap();
+
+        // A partially written record at the end is truncated away.
+        let mut file = OpenOptions::new().append(true).open(&path).await.unwrap();
+        file.write_all(&[0, 0, 0]).await.unwrap();
+        file.flush().await.unwrap();
+        drop(file);
+        let (mut wal, remaining) = WAL::<String>::open(path.clone()).await.unwrap();
+        assert_eq!(remaining, vec!["Hi!", "Hello there!"]);
+        wal.write(&"Sup bro".to_string()).await.unwrap();
+        wal.close().await.unwrap();
+        let (wal, remaining) = WAL::<String>::open(path.clone()).await.unwrap();
+        assert_eq!(remaining, vec!["Hi!", "Hello there!", "Sup bro"]);
+        wal.close().await.unwrap();
+
+        // A damaged record followed by valid ones is reported.
+        let file = OpenOptions::new().write(true).open(&path).await.unwrap();
+        let mut file = file.into_std().await;
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(25)).unwrap();
+        std::io::Write::write_all(&mut file, b"!").unwrap();
+        drop(file);
+        let err = WAL::<String>::open(path.clone()).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<WALError>(),
+            Some(&WALError::Corrupted {
+                path: path.clone(),
+                offset: 8,
+            })
+        );
+    }
+
+    #[tokio::test]
+    pub async fn damaged_length_test() {
+        let dir = TempDir::new();
+        let path = dir.join(format!("{}.wal", Key::new().hex()));
+        let mut wal = WAL::<String>::create(path.clone()).await.unwrap();
+        wal.write(&"Hi!".to_string()).await.unwrap();
+        wal.write(&"Hello there!".to_string()).await.unwrap();
+        wal.close().await.unwrap();
+
+        // A length pointing past the end of the file is not taken for a torn
+        // record, which would truncate the records after it.
+        let file = OpenOptions::new().write(true).open(&path).await.unwrap();
+        let mut file = file.into_std().await;
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(8)).unwrap();
+        std::io::Write::write_all(&mut file, &[0xff]).unwrap();
+        drop(file);
+        let err = WAL::<String>::read_with(path.clone(), |x: String| x)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<WALError>(),
+            Some(&WALError::Corrupted {
+                path: path.clone(),
+                offset: 8,
+            })
+        );
+        assert!(WAL::<String>::open(path.clone()).await.is_err());
+    }
+
+    #[tokio::test]
+    pub async fn legacy_test() {
+        let dir = TempDir::new();
+        let path = dir.join(format!("{}.wal", Key::new().hex()));
+        let mut bytes = Vec::new();
+        for x in ["Hi!", "Hello there!"] {
+            let payload = bincode::serialize(x).unwrap();
+            bytes.extend((payload.len() as u64).to_be_bytes());
+            bytes.extend(payload);
+        }
+        tokio::fs::write(&path, &bytes).await.unwrap();
+        let (wal, remaining) = WAL::<String>::open(path.clone()).await.unwrap();
+        assert_eq!(remaining, vec!["Hi!", "Hello there!"]);
+        wal.close().await.unwrap();
+        let (wal, remaining) = WAL::<String>::open(path.clone()).await.unwrap();
+        assert_eq!(remaining, vec!["Hi!", "Hello there!"]);
+        wal.close().await.unwrap();
+
+        // A record torn by a crash is truncated away, as in newer logs.
+        let torn = bincode::serialize("Sup bro").unwrap();
+        for n in [3, 8 + torn.len() - 1] {
+            let mut file = bytes.clone();
+            file.extend((torn.len() as u64).to_be_bytes());
+            file.extend(&torn);
+            file.truncate(bytes.len() + n);
+            tokio::fs::write(&path, &file).await.unwrap();
+            let (remaining, torn) = WAL::<String>::read_with(path.clone(), |x: String| x)
+                .await
+                .unwrap();
+            assert_eq!(torn, n as u64);
+            assert_eq!(
+                remaining,
+                vec![
+                    ("Hi!".to_string(), None),
+                    ("Hello there!".to_string(), None)
+                ]
+            );
+            let (wal, remaining) = WAL::<String>::open(path.clone()).await.unwrap();
+            assert_eq!(remaining, vec!["Hi!", "Hello there!"]);
+            wal.close().await.unwrap();
+            let (wal, remaining) = WAL::<String>::open(path.clone()).await.unwrap();
+            assert_eq!(remaining, vec!["Hi!", "Hello there!"]);
+            wal.close().await.unwrap();
+        }
+
+        // A file that is not a log is left alone rather than wiped.
+        let bytes = b"Not a log at all".to_vec();
+        tokio::fs::write(&path, &bytes).await.unwrap();
+        assert!(WAL::<String>::open(path.clone()).await.is_err());
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), bytes);
+    }
 }
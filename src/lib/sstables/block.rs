@@ -0,0 +1,188 @@
+use std::mem::size_of;
+
+use anyhow::{anyhow, Result};
+use rocket::serde::{Deserialize, DeserializeOwned, Serialize};
+
+use crate::core::{
+    entry::{Entry, EntryData},
+    key::Key,
+};
+
+/// Blocks are closed once they grow past this many bytes.
+pub(super) const BLOCK_SIZE: usize = 4096;
+
+const MAGIC: [u8; 4] = *b"LSST";
+pub(super) const VERSION: u32 = 1;
+pub(super) const FOOTER_SIZE: usize = 5 * size_of::<u64>() + size_of::<u32>() + MAGIC.len();
+
+/// Locates a block within an `.sst` file. The index of a table holds one
+/// handle per block, and is kept in memory while the table is open.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub struct BlockHandle {
+    pub first_key: Key,
+    pub offset: u64,
+    pub length: u64,
+    /// The index of the first entry of the block within the table.
+    pub start: u64,
+}
+
+/// How the data blocks of a table are compressed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(crate = "rocket::serde")]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+}
+
+impl Compression {
+    pub fn compress(self, block: Vec<u8>) -> Vec<u8> {
+        match self {
+            Compression::None => block,
+            Compression::Lz4 => lz4_flex::compress_prepend_size(&block),
+        }
+    }
+
+    pub fn decompress(self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&bytes)
+                .map_err(|e| anyhow!("Block could not be decompressed: {}", e)),
+        }
+    }
+}
+
+/// The index of an `.sst` file, which follows the data blocks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub struct TableIndex {
+    pub compression: Compression,
+    pub blocks: Vec<BlockHandle>,
+}
+
+/// The fixed-size trailer of an `.sst` file, which points at the index and
+/// bloom filter that follow the data blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+    pub index_offset: u64,
+    pub index_length: u64,
+    pub bloom_offset: u64,
+    pub bloom_length: u64,
+    pub entries: u64,
+    /// The format version of the table.
+    pub version: u32,
+}
+
+impl Footer {
+    pub fn to_bytes(self) -> Vec<u8> {
+        [
+            &self.index_offset.to_be_bytes()[..],
+            &self.index_length.to_be_bytes(),
+            &self.bloom_offset.to_be_bytes(),
+            &self.bloom_length.to_be_bytes(),
+            &self.entries.to_be_bytes(),
+            &self.version.to_be_bytes(),
+            &MAGIC,
+        ]
+        .concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8; FOOTER_SIZE]) -> Result<Footer> {
+        let (fields, trailer) = bytes.split_at(5 * size_of::<u64>());
+        if trailer[size_of::<u32>()..] != MAGIC {
+            return Err(anyhow!("Table footer has an invalid magic number!"));
+        }
+        let version = u32::from_be_bytes(trailer[..size_of::<u32>()].try_into().unwrap());
+        if version != VERSION {
+            return Err(anyhow!("Table has unsupported version {}!", version));
+        }
+        let field = |i: usize| u64::from_be_bytes(fields[i * 8..(i + 1) * 8].try_into().unwrap());
+        Ok(Footer {
+            index_offset: field(0),
+            index_length: field(1),
+            bloom_offset: field(2),
+            bloom_length: field(3),
+            entries: field(4),
+            version,
+        })
+    }
+}
+
+/// Appends an entry to a block: the length-prefixed serialized key, followed
+/// by the length-prefixed serialized data and the sequence number.
+pub fn encode_entry<T: Serialize>(block: &mut Vec<u8>, entry: &Entry<T>) -> Result<()> {
+    let key_bytes = bincode::serialize(&entry.key)?;
+    let data_bytes = bincode::serialize(&entry.data)?;
+    block.extend((key_bytes.len() as u32).to_be_bytes());
+    block.extend(key_bytes);
+    block.extend((data_bytes.len() as u32).to_be_bytes());
+    block.extend(data_bytes);
+    block.extend(entry.sequence.to_be_bytes());
+    Ok(())
+}
+
+fn split_field(bytes: &[u8]) -> Result<(&[u8], &[u8])> {
+    if bytes.len() < size_of::<u32>() {
+        return Err(anyhow!("Block entry is truncated!"));
+    }
+    let (length, rest) = bytes.split_at(size_of::<u32>());
+    let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+    if rest.len() < length {
+        return Err(anyhow!("Block entry is truncated!"));
+    }
+    Ok(rest.split_at(length))
+}
+
+/// A data block read from disk, decoded lazily one entry at a time.
+pub struct Block {
+    bytes: Vec<u8>,
+    entries: Vec<usize>,
+}
+
+impl Block {
+    pub fn new(bytes: Vec<u8>) -> Result<Block> {
+        let mut entries = Vec::new();
+        let mut remaining = bytes.as_slice();
+        while !remaining.is_empty() {
+            entries.push(bytes.len() - remaining.len());
+            let (_, rest) = split_field(remaining)?;
+            let (_, rest) = split_field(rest)?;
+            if rest.len() < size_of::<u64>() {
+                return Err(anyhow!("Block entry is truncated!"));
+            }
+            remaining = &rest[size_of::<u64>()..];
+        }
+        Ok(Block { bytes, entries })
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the bytes from the start of the entry to the end of the block.
+    /// A corrupt index may point past the last entry.
+    fn tail(&self, index: usize) -> Result<&[u8]> {
+        match self.entries.get(index) {
+            Some(&x) => Ok(&self.bytes[x..]),
+            None => Err(anyhow!("Block has no entry {}!", index)),
+        }
+    }
+
+    pub fn key(&self, index: usize) -> Result<Key> {
+        let (key, _) = split_field(self.tail(index)?)?;
+        Ok(bincode::deserialize(key)?)
+    }
+
+    pub fn entry<T: DeserializeOwned>(&self, index: usize) -> Result<Entry<T>> {
+        let (key, rest) = split_field(self.tail(index)?)?;
+        let (data, rest) = split_field(rest)?;
+        let sequence = u64::from_be_bytes(rest[..size_of::<u64>()].try_into().unwrap());
+        Ok(Entry {
+            key: bincode::deserialize(key)?,
+            data: bincode::deserialize::<EntryData<T>>(data)?,
+            sequence,
+        })
+    }
+}
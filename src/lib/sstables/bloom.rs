@@ -0,0 +1,110 @@
+use std::mem::size_of;
+
+use crate::{core::key::Key, Error, Result};
+
+const BITS_PER_KEY: usize = 10;
+const NUM_HASHES: u32 = 7;
+/// The most hashes a filter read from disk may use, far more than any
+/// useful filter needs.
+const MAX_HASHES: u32 = 30;
+
+/// A bloom filter over the keys of a table. Lookups of keys that were never
+/// inserted return false with high probability, so tables that cannot hold a
+/// key can be skipped without touching the disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    num_hashes: u32,
+    bits: Vec<u8>,
+}
+
+/// 64-bit FNV-1a, which unlike the standard library hashers is guaranteed to
+/// be stable across builds.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl BloomFilter {
+    pub fn new(expected_keys: usize) -> BloomFilter {
+        let bytes = (expected_keys.max(1) * BITS_PER_KEY).div_ceil(8);
+        BloomFilter {
+            num_hashes: NUM_HASHES,
+            bits: vec![0; bytes],
+        }
+    }
+
+    /// Derives the bit positions for a key from a single hash, using double
+    /// hashing.
+    fn positions(&self, key: &Key) -> impl Iterator<Item = usize> {
+        let hash = fnv1a(key.bytes());
+        let (h1, h2) = (hash as u32, (hash >> 32) as u32 | 1);
+        let len = self.bits.len() * 8;
+        (0..self.num_hashes).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) as usize % len)
+    }
+
+    pub fn insert(&mut self, key: &Key) {
+        for p in self.positions(key) {
+            self.bits[p / 8] |= 1 << (p % 8);
+        }
+    }
+
+    pub fn contains(&self, key: &Key) -> bool {
+        self.positions(key)
+            .all(|p| self.bits[p / 8] & (1 << (p % 8)) != 0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.num_hashes.to_be_bytes()[..], &self.bits].concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BloomFilter> {
+        if bytes.len() <= size_of::<u32>() {
+            return Err(Error::Corruption("Bloom filter is truncated".to_string()));
+        }
+        let (num_hashes, bits) = bytes.split_at(size_of::<u32>());
+        let num_hashes = u32::from_be_bytes(num_hashes.try_into().unwrap());
+        if !(1..=MAX_HASHES).contains(&num_hashes) {
+            return Err(Error::Corruption(format!(
+                "Bloom filter uses {} hashes",
+                num_hashes
+            )));
+        }
+        Ok(BloomFilter {
+            num_hashes,
+            bits: bits.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{core::key::Key, Error};
+
+    use super::BloomFilter;
+
+    #[test]
+    fn test() {
+        let keys: Vec<_> = (0..1000).map(|_| Key::new()).collect();
+        let mut filter = BloomFilter::new(keys.len());
+        for k in keys.iter() {
+            filter.insert(k);
+        }
+        let filter = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+        assert!(keys.iter().all(|k| filter.contains(k)));
+        let false_positives = (0..1000).filter(|_| filter.contains(&Key::new())).count();
+        assert!(false_positives < 50);
+
+        let bytes = filter.to_bytes();
+        for bad in [
+            &bytes[..4],
+            &[&0u32.to_be_bytes()[..], &bytes[4..]].concat(),
+            &[&31u32.to_be_bytes()[..], &bytes[4..]].concat(),
+        ] {
+            assert!(matches!(
+                BloomFilter::from_bytes(bad),
+                Err(Error::Corruption(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    ops::RangeBounds,
+    sync::{atomic::AtomicU64, Arc},
+};
+
+use dashmap::{DashMap, DashSet};
+
+use crate::core::{
+    entry::{Entry, EntryData},
+    key::Key,
+};
+
+/// The sequence number of the most recent write to a tree, shared by all of
+/// its buffers. A write is only visible once the sequence has been advanced
+/// past it.
+pub type Sequence = Arc<AtomicU64>;
+
+/// The versions of a single key, oldest first, tagged with their sequence
+/// numbers.
+type Versions<T> = Vec<(u64, EntryData<T>)>;
+
+/// The entries held in memory by a write buffer. Every version of each key
+/// is kept, so that snapshots can read the buffer as it was when they were
+/// taken.
+#[derive(Debug)]
+pub struct Memtable<T> {
+    entries: Arc<DashMap<Key, Versions<T>>>,
+    /// The sequences of writes that were never logged, which are left out of
+    /// the changes.
+    unlogged: Arc<DashSet<u64>>,
+}
+
+impl<T> Clone for Memtable<T> {
+    fn clone(&self) -> Self {
+        Memtable {
+            entries: self.entries.clone(),
+            unlogged: self.unlogged.clone(),
+        }
+    }
+}
+
+impl<T: Clone> Memtable<T> {
+    pub fn new() -> Memtable<T> {
+        Memtable {
+            entries: Arc::new(DashMap::new()),
+            unlogged: Arc::new(DashSet::new()),
+        }
+    }
+
+    pub fn insert(&self, key: Key, sequence: u64, data: EntryData<T>) {
+        self.entries.entry(key).or_default().push((sequence, data));
+    }
+
+    /// Records that the write with the sequence number was not logged.
+    pub fn insert_unlogged(&self, sequence: u64) {
+        self.unlogged.insert(sequence);
+    }
+
+    /// Returns the newest version of the key written at or before the
+    /// sequence number, along with the sequence number it was written at.
+    pub fn get(&self, key: &Key, sequence: u64) -> Option<(u64, EntryData<T>)> {
+        let versions = self.entries.get(key)?;
+        versions.iter().rev().find(|x| x.0 <= sequence).cloned()
+    }
+
+    /// Returns the newest version of each key within the range written at or
+    /// before the sequence number, in key order.
+    pub fn range(&self, range: &impl RangeBounds<Key>, sequence: u64) -> Vec<(Key, EntryData<T>)> {
+        let mut entries: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|x| range.contains(x.key()))
+            .filter_map(|x| {
+                let (_, data) = x.value().iter().rev().find(|v| v.0 <= sequence)?;
+                Some((x.key().clone(), data.clone()))
+            })
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// Returns the newest version of every key written at or before the
+    /// sequence number, in key order.
+    pub fn latest(&self, sequence: u64) -> Vec<Entry<T>> {
+        let mut entries: Vec<_> = self
+            .entries
+            .iter()
+            .filter_map(|x| {
+                let (s, data) = x.value().iter().rev().find(|v| v.0 <= sequence)?;
+                Some(Entry {
+                    key: x.key().clone(),
+                    data: data.clone(),
+                    sequence: *s,
+                })
+            })
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        entries
+    }
+
+    /// Returns every logged version written after `after` and at or before
+    /// `until`, ordered by sequence number and then key.
+    pub fn changes(&self, after: u64, until: u64) -> Vec<Entry<T>> {
+        let mut entries: Vec<_> = self
+            .entries
+            .iter()
+            .flat_map(|x| {
+                x.value()
+                    .iter()
+                    .filter(|v| v.0 > after && v.0 <= until && !self.unlogged.contains(&v.0))
+                    .map(|(s, data)| Entry {
+                        key: x.key().clone(),
+                        data: data.clone(),
+                        sequence: *s,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        entries.sort_by(|a, b| (a.sequence, &a.key).cmp(&(b.sequence, &b.key)));
+        entries
+    }
+
+    /// Returns the number of distinct keys.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<T: Clone> Default for Memtable<T> {
+    fn default() -> Self {
+        Memtable::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{entry::EntryData, key::Key};
+
+    use super::Memtable;
+
+    #[test]
+    fn test_versions() {
+        let memtable = Memtable::new();
+        let (k1, k2) = (Key::from("a"), Key::from("b"));
+        memtable.insert(k1.clone(), 1, EntryData::Data("a1"));
+        memtable.insert(k2.clone(), 2, EntryData::Data("b2"));
+        memtable.insert(k1.clone(), 3, EntryData::Deleted);
+
+        assert_eq!(memtable.get(&k1, 0), None);
+        assert_eq!(memtable.get(&k1, 2), Some((1, EntryData::Data("a1"))));
+        assert_eq!(memtable.get(&k1, u64::MAX), Some((3, EntryData::Deleted)));
+        assert_eq!(
+            memtable.range(&(..), 2),
+            vec![(k1, EntryData::Data("a1")), (k2, EntryData::Data("b2"))]
+        );
+        assert_eq!(
+            memtable
+                .changes(1, 3)
+                .iter()
+                .map(|x| x.sequence)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(memtable.len(), 2);
+    }
+}
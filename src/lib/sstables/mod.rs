@@ -1,3 +1,7 @@
+pub mod block;
+pub mod bloom;
+pub mod memtable;
 pub mod sstable;
 pub mod sstable_builder;
+pub mod sstable_writer;
 pub mod write_buffer;
@@ -1,22 +1,45 @@
-use std::{cmp::Ordering, marker::PhantomData, path::Path};
+use std::{
+    cmp::Ordering,
+    marker::PhantomData,
+    ops::Bound,
+    path::{Path, PathBuf},
+};
 
-use anyhow::{anyhow, Result};
 use rocket::{
     futures::future::join,
     serde::{Deserialize, DeserializeOwned, Serialize},
-    tokio::join,
+    tokio::{fs::metadata, join},
 };
 
 use crate::{
     core::{
-        entry::EntryData,
+        entry::{Entry, EntryData},
         key::{Key, KEY_SIZE},
     },
     persistance::files::{FileReader, ImmutableFile},
+    Error, Result,
+};
+
+use super::{
+    block::{Block, BlockHandle, Compression, Footer, TableIndex, FOOTER_SIZE},
+    bloom::BloomFilter,
 };
 
+/// Size of an entry in a legacy `.offsets` file: the key followed by the
+/// offset and length of the data in the `.strings` file.
 const ENTRY_SIZE: usize = KEY_SIZE + 16;
 
+/// The on-disk layout of a table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Legacy `.offsets` file pointing at data in the `.strings` file, which
+    /// only supports fixed-size keys.
+    Fixed,
+    /// Single `.sst` file of data blocks, followed by a sparse index of the
+    /// first key in each block, a bloom filter and a footer.
+    Block,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct OffsetEntry {
@@ -25,74 +48,437 @@ pub struct OffsetEntry {
     pub length: u64,
 }
 
+#[derive(Debug)]
+enum Files {
+    Split {
+        offsets: ImmutableFile,
+        strings: ImmutableFile,
+    },
+    Single {
+        file: ImmutableFile,
+        index: Vec<BlockHandle>,
+        entries: u64,
+        compression: Compression,
+    },
+}
+
 #[derive(Debug)]
 pub struct SSTable<T> {
     id: String,
-    offsets: ImmutableFile,
-    strings: ImmutableFile,
+    layout: Layout,
+    files: Files,
+    bloom: Option<BloomFilter>,
+    /// The first and last keys in the table, or `None` if it is empty.
+    range: Option<(Key, Key)>,
+    /// The sequence given to the table when it was ingested, which its
+    /// entries are read with in place of the ones they were written with.
+    sequence: Option<u64>,
     entry_type: PhantomData<T>,
 }
 
+async fn load_split(path: PathBuf) -> Result<Files> {
+    let offsets = ImmutableFile::from_existing(path.with_extension("offsets")).await?;
+    if offsets.size() % ENTRY_SIZE as u64 != 0 {
+        return Err(Error::Corruption(format!(
+            "{} does not hold a whole number of entries",
+            offsets.path().display()
+        )));
+    }
+    Ok(Files::Split {
+        offsets,
+        strings: ImmutableFile::from_existing(path.with_extension("strings")).await?,
+    })
+}
+
+/// Reads the footer, index and bloom filter of an `.sst` file.
+async fn load_single(path: PathBuf) -> Result<(Files, BloomFilter)> {
+    let file = ImmutableFile::from_existing(path).await?;
+    if file.size() < FOOTER_SIZE as u64 {
+        return Err(Error::Corruption(format!(
+            "Table {} is too short to hold a footer",
+            file.path().display()
+        )));
+    }
+    let mut reader = file.new_reader().await?;
+    let footer: [u8; FOOTER_SIZE] = reader.read_fixed(file.size() - FOOTER_SIZE as u64).await?;
+    let footer = Footer::from_bytes(&footer)?;
+    let index = reader
+        .read(footer.index_offset, footer.index_length)
+        .await?;
+    let bloom = reader
+        .read(footer.bloom_offset, footer.bloom_length)
+        .await?;
+    drop(reader);
+    let index: TableIndex = bincode::deserialize(&index)?;
+    // Lookups assume the blocks start at entry 0 and partition the table.
+    let blocks = &index.blocks;
+    if blocks.first().map_or(footer.entries > 0, |x| x.start != 0)
+        || blocks.windows(2).any(|x| x[0].start >= x[1].start)
+        || blocks.last().is_some_and(|x| x.start >= footer.entries)
+    {
+        return Err(Error::Corruption(format!(
+            "Table {} has an invalid block index",
+            file.path().display()
+        )));
+    }
+    let files = Files::Single {
+        index: index.blocks,
+        entries: footer.entries,
+        compression: index.compression,
+        file,
+    };
+    Ok((files, BloomFilter::from_bytes(&bloom)?))
+}
+
 impl<T> SSTable<T> {
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u64 {
-        self.offsets.size() / (ENTRY_SIZE as u64)
+        match &self.files {
+            Files::Split { offsets, .. } => offsets.size() / ENTRY_SIZE as u64,
+            Files::Single { entries, .. } => *entries,
+        }
     }
 
-    pub async fn new(dir: &Path, id: String) -> SSTable<T> {
-        SSTable {
-            offsets: ImmutableFile::from_existing(dir.join(&id).with_extension("offsets"))
-                .await
-                .unwrap(),
-            strings: ImmutableFile::from_existing(dir.join(&id).with_extension("strings"))
-                .await
-                .unwrap(),
-            entry_type: PhantomData::default(),
+    pub async fn new(dir: &Path, id: String) -> Result<SSTable<T>> {
+        let path = dir.join(&id);
+        let (layout, files, bloom) = match metadata(path.with_extension("sst")).await {
+            Ok(_) => {
+                let (files, bloom) = load_single(path.with_extension("sst")).await?;
+                (Layout::Block, files, Some(bloom))
+            }
+            Err(_) => (Layout::Fixed, load_split(path).await?, None),
+        };
+        let mut table = SSTable {
+            layout,
+            files,
+            bloom,
+            range: None,
+            sequence: None,
+            entry_type: PhantomData,
             id,
+        };
+        if table.len() > 0 {
+            let mut reader = table.reader().await?;
+            let first = reader.read_key(0).await?;
+            let last = reader.read_key(reader.len() - 1).await?;
+            drop(reader);
+            table.range = Some((first, last));
         }
+        Ok(table)
     }
 
     pub fn id(&self) -> &str {
         &self.id
     }
 
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Returns the path of the table's file, or `None` if it uses one of the
+    /// older layouts that split it across several files.
+    pub fn path(&self) -> Option<&Path> {
+        match &self.files {
+            Files::Single { file, .. } => Some(file.path()),
+            Files::Split { .. } => None,
+        }
+    }
+
+    /// Returns the paths of every file that makes up the table.
+    pub fn files(&self) -> Vec<&Path> {
+        match &self.files {
+            Files::Single { file, .. } => vec![file.path()],
+            Files::Split { offsets, strings } => vec![offsets.path(), strings.path()],
+        }
+    }
+
+    /// Returns how the blocks of the table are compressed.
+    pub fn compression(&self) -> Compression {
+        match &self.files {
+            Files::Single { compression, .. } => *compression,
+            Files::Split { .. } => Compression::None,
+        }
+    }
+
+    /// Returns the sequence given to the table when it was ingested, if its
+    /// entries are read with it.
+    pub fn sequence(&self) -> Option<u64> {
+        self.sequence
+    }
+
+    /// Makes every entry of the table read with the sequence, so that the
+    /// entries of an ingested table are newer than the writes it hides.
+    pub fn set_sequence(&mut self, sequence: u64) {
+        self.sequence = Some(sequence);
+    }
+
+    /// Returns the first and last keys in the table, or `None` if it is empty.
+    pub fn range(&self) -> Option<(&Key, &Key)> {
+        self.range.as_ref().map(|(first, last)| (first, last))
+    }
+
+    /// Returns the total size of the table's files in bytes.
+    pub fn size(&self) -> u64 {
+        match &self.files {
+            Files::Split { offsets, strings } => offsets.size() + strings.size(),
+            Files::Single { file, .. } => file.size(),
+        }
+    }
+
+    /// Returns false if the table definitely does not contain the key. Tables
+    /// written without a bloom filter may contain any key.
+    pub fn may_contain(&self, key: &Key) -> bool {
+        match &self.bloom {
+            Some(filter) => filter.contains(key),
+            None => true,
+        }
+    }
+
     pub async fn reader(&self) -> Result<SSTableReader<'_, T>> {
-        let (offsets, strings) = join!(self.offsets.new_reader(), self.strings.new_reader());
+        let files = match &self.files {
+            Files::Split { offsets, strings } => {
+                let (offsets, strings) = join!(offsets.new_reader(), strings.new_reader());
+                ReaderFiles::Split {
+                    offsets: offsets?,
+                    strings: strings?,
+                }
+            }
+            Files::Single {
+                file,
+                index,
+                compression,
+                ..
+            } => ReaderFiles::Single {
+                file: file.new_reader().await?,
+                index,
+                compression: *compression,
+                block: None,
+            },
+        };
         Ok(SSTableReader {
-            offsets: offsets?,
-            strings: strings?,
+            len: self.len(),
+            files,
+            sequence: self.sequence,
             entry_type: self.entry_type,
         })
     }
 
     pub async fn delete(self) -> Result<()> {
-        let deletions = join(self.offsets.delete(), self.strings.delete()).await;
-        match deletions {
-            (Ok(_), Ok(_)) => Ok(()),
-            _ => Err(anyhow!("Failed to delete SSTable!")),
+        match self.files {
+            Files::Split { offsets, strings } => {
+                let (offsets, strings) = join(offsets.delete(), strings.delete()).await;
+                offsets?;
+                strings?;
+                Ok(())
+            }
+            Files::Single { file, .. } => Ok(file.delete().await?),
         }
     }
 }
 
+enum ReaderFiles<'a> {
+    Split {
+        offsets: FileReader<'a>,
+        strings: FileReader<'a>,
+    },
+    Single {
+        file: FileReader<'a>,
+        index: &'a [BlockHandle],
+        compression: Compression,
+        /// The most recently read block, which sequential reads will reuse.
+        block: Option<(usize, Block)>,
+    },
+}
+
 pub struct SSTableReader<'a, T> {
-    offsets: FileReader<'a>,
-    strings: FileReader<'a>,
+    len: u64,
+    files: ReaderFiles<'a>,
+    /// Replaces the sequence of every entry read, if set.
+    sequence: Option<u64>,
     entry_type: PhantomData<T>,
 }
 
-impl<'a, T: DeserializeOwned> SSTableReader<'a, T> {
+impl<'a, T> SSTableReader<'a, T> {
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u64 {
-        self.offsets.size() / (ENTRY_SIZE as u64)
+        self.len
+    }
+
+    pub async fn read_key(&mut self, index: u64) -> Result<Key> {
+        if let ReaderFiles::Single { index: handles, .. } = &self.files {
+            let b = handles.partition_point(|h| h.start <= index) - 1;
+            let start = handles[b].start;
+            let block = self.read_block(b).await?;
+            return Ok(block.key((index - start) as usize)?);
+        }
+        Ok(self.read_offset(index).await?.key)
+    }
+
+    /// Returns the index of the first entry that lies after the given lower
+    /// bound, or `len()` if there is no such entry.
+    pub async fn lower_bound(&mut self, bound: Bound<&Key>) -> Result<u64> {
+        let (key, inclusive) = match bound {
+            Bound::Included(k) => (k, true),
+            Bound::Excluded(k) => (k, false),
+            Bound::Unbounded => return Ok(0),
+        };
+        let before = |k: &Key| match k.cmp(key) {
+            Ordering::Less => true,
+            Ordering::Equal => !inclusive,
+            Ordering::Greater => false,
+        };
+
+        if let ReaderFiles::Single { index, .. } = &self.files {
+            // Every entry after the last block starting at or before the key
+            // lies after the bound.
+            let b = index.partition_point(|h| h.first_key <= *key);
+            let b = match b.checked_sub(1) {
+                Some(b) => b,
+                None => return Ok(0),
+            };
+            let start = index[b].start;
+            let block = self.read_block(b).await?;
+            let mut i = 0;
+            while i < block.len() && before(&block.key(i)?) {
+                i += 1;
+            }
+            return Ok(start + i as u64);
+        }
+
+        let mut lower = 0;
+        let mut upper = self.len();
+        while lower < upper {
+            let mid = (lower + upper) / 2;
+            let offset = self.read_offset(mid).await?;
+            if before(&offset.key) {
+                lower = mid + 1;
+            } else {
+                upper = mid;
+            }
+        }
+        Ok(lower)
+    }
+
+    async fn read_block(&mut self, b: usize) -> Result<&Block> {
+        let (file, index, compression, block) = match &mut self.files {
+            ReaderFiles::Single {
+                file,
+                index,
+                compression,
+                block,
+            } => (file, index, *compression, block),
+            ReaderFiles::Split { .. } => unreachable!(),
+        };
+        if !matches!(block, Some((cached, _)) if *cached == b) {
+            let handle = &index[b];
+            let bytes = file.read(handle.offset, handle.length).await?;
+            let bytes = compression.decompress(bytes)?;
+            *block = Some((b, Block::new(bytes)?));
+        }
+        Ok(&block.as_ref().unwrap().1)
+    }
+
+    async fn read_offset(&mut self, index: u64) -> Result<OffsetEntry> {
+        let offsets = match &mut self.files {
+            ReaderFiles::Split { offsets, .. } => offsets,
+            ReaderFiles::Single { .. } => unreachable!(),
+        };
+        let buf: [u8; ENTRY_SIZE] = offsets.read_fixed(index * ENTRY_SIZE as u64).await?;
+        let key: [u8; KEY_SIZE] = buf[..KEY_SIZE].try_into().unwrap();
+        let offset = u64::from_be_bytes(buf[KEY_SIZE..KEY_SIZE + 8].try_into().unwrap());
+        let length = u64::from_be_bytes(buf[KEY_SIZE + 8..].try_into().unwrap());
+        Ok(OffsetEntry {
+            key: Key::Key(key),
+            offset,
+            length,
+        })
     }
+}
+
+impl<T: DeserializeOwned> SSTable<T> {
+    /// Reads every entry of the table, checking that the keys are strictly
+    /// increasing and that every value can be decoded.
+    pub async fn verify(&self) -> Result<()> {
+        let mut reader = self.reader().await?;
+        if let Files::Single { index, .. } = &self.files {
+            for handle in index {
+                if reader.read_key(handle.start).await? != handle.first_key {
+                    return Err(Error::Corruption(format!(
+                        "Block at offset {} of table {} is indexed under the wrong key",
+                        handle.offset, self.id
+                    )));
+                }
+            }
+        }
+        let mut last: Option<Key> = None;
+        for i in 0..reader.len() {
+            let entry = reader.read_index(i).await?.unwrap();
+            if matches!(&last, Some(k) if *k >= entry.key) {
+                return Err(Error::Corruption(format!(
+                    "Keys of table {} are out of order at entry {}",
+                    self.id, i
+                )));
+            }
+            last = Some(entry.key);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T: DeserializeOwned> SSTableReader<'a, T> {
+    pub async fn read(&mut self, key: &Key) -> Result<Option<EntryData<T>>> {
+        Ok(self.read_entry(key).await?.map(|x| x.data))
+    }
+
+    /// Returns the entry for the key, including its sequence number. Tables
+    /// written before sequence numbers were recorded report sequence 0.
+    pub async fn read_entry(&mut self, key: &Key) -> Result<Option<Entry<T>>> {
+        let entry = self.read_written_entry(key).await?;
+        Ok(entry.map(|x| self.with_sequence(x)))
+    }
+
+    pub async fn read_index(&mut self, index: u64) -> Result<Option<Entry<T>>> {
+        let entry = self.read_written_index(index).await?;
+        Ok(entry.map(|x| self.with_sequence(x)))
+    }
+
+    fn with_sequence(&self, mut entry: Entry<T>) -> Entry<T> {
+        if let Some(sequence) = self.sequence {
+            entry.sequence = sequence;
+        }
+        entry
+    }
+
+    /// Returns the entry for the key as it was written to the table.
+    async fn read_written_entry(&mut self, key: &Key) -> Result<Option<Entry<T>>> {
+        if let ReaderFiles::Single { index, .. } = &self.files {
+            let block = match index
+                .partition_point(|h| h.first_key <= *key)
+                .checked_sub(1)
+            {
+                Some(b) => b,
+                None => return Ok(None),
+            };
+            let block = self.read_block(block).await?;
+            let mut lower = 0;
+            let mut upper = block.len();
+            while lower < upper {
+                let mid = (lower + upper) / 2;
+                match key.cmp(&block.key(mid)?) {
+                    Ordering::Equal => return Ok(Some(block.entry(mid)?)),
+                    Ordering::Less => upper = mid,
+                    Ordering::Greater => lower = mid + 1,
+                }
+            }
+            return Ok(None);
+        }
 
-    pub async fn read(&mut self, key: &Key) -> Option<EntryData<T>> {
         let mut lower = 0;
-        let mut upper = self.offsets.size() / (ENTRY_SIZE as u64);
+        let mut upper = self.len();
         let mut found = None;
         while lower < upper {
             let mid = (lower + upper) / 2;
-            let offset = self.read_offset(mid).await.unwrap();
+            let offset = self.read_offset(mid).await?;
             match key.cmp(&offset.key) {
                 Ordering::Equal => {
                     found = Some(offset);
@@ -106,120 +492,349 @@ impl<'a, T: DeserializeOwned> SSTableReader<'a, T> {
                 }
             }
         }
-        let offset = found?;
-        Some(self.read_string(&offset).await.unwrap())
-    }
-
-    pub async fn read_index(&mut self, index: u64) -> Option<(Key, EntryData<T>)> {
-        if index < self.len() {
-            let offset = self.read_offset(index).await.unwrap();
-            let data = self.read_string(&offset).await.unwrap();
-            Some((offset.key, data))
-        } else {
-            None
+        match found {
+            Some(offset) => {
+                let data = self.read_string(&offset).await?;
+                Ok(Some(Entry::new(offset.key, data)))
+            }
+            None => Ok(None),
         }
     }
 
-    async fn read_offset(&mut self, index: u64) -> Result<OffsetEntry> {
-        let buf: [u8; ENTRY_SIZE] = self.offsets.read_fixed(index * ENTRY_SIZE as u64).await?;
-        let key: [u8; KEY_SIZE] = buf[..KEY_SIZE].try_into().unwrap();
-        let offset = u64::from_be_bytes(buf[KEY_SIZE..KEY_SIZE + 8].try_into().unwrap());
-        let length = u64::from_be_bytes(buf[KEY_SIZE + 8..].try_into().unwrap());
-        Ok(OffsetEntry {
-            key: Key::Key(key),
-            offset,
-            length,
-        })
+    /// Returns the entry at the index as it was written to the table.
+    async fn read_written_index(&mut self, index: u64) -> Result<Option<Entry<T>>> {
+        if index >= self.len() {
+            return Ok(None);
+        }
+        if let ReaderFiles::Single { index: handles, .. } = &self.files {
+            let b = handles.partition_point(|h| h.start <= index) - 1;
+            let start = handles[b].start;
+            let block = self.read_block(b).await?;
+            return Ok(Some(block.entry((index - start) as usize)?));
+        }
+        let offset = self.read_offset(index).await?;
+        let data = self.read_string(&offset).await?;
+        Ok(Some(Entry::new(offset.key, data)))
     }
 
     async fn read_string(
         &mut self,
         OffsetEntry { offset, length, .. }: &OffsetEntry,
     ) -> Result<EntryData<T>> {
-        let buf = self.strings.read(*offset, *length).await?;
+        let strings = match &mut self.files {
+            ReaderFiles::Split { strings, .. } => strings,
+            ReaderFiles::Single { .. } => unreachable!(),
+        };
+        let buf = strings.read(*offset, *length).await?;
         Ok(bincode::deserialize(&buf)?)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::{ops::Bound, path::PathBuf, time::Duration};
 
-    use rocket::tokio;
+    use rocket::tokio::{self, sync::broadcast::channel};
 
     use crate::{
         core::{
             entry::{Entry, EntryData},
             key::Key,
         },
-        sstables::{sstable_builder::SSTableBuilder, write_buffer::WriteBuffer},
+        persistance::files::ImmutableFile,
+        sstables::{
+            block::Compression,
+            memtable::Sequence,
+            sstable_builder::SSTableBuilder,
+            write_buffer::{Durability, WriteBuffer},
+        },
+        testing::TempDir,
     };
 
-    use super::SSTable;
+    use super::{Layout, SSTable};
 
     async fn build_sstable(
         sequence: impl IntoIterator<Item = Entry<String>>,
         dir: PathBuf,
     ) -> SSTable<String> {
-        let wb = WriteBuffer::create(dir).await;
+        build_compressed(sequence, dir, Compression::None).await
+    }
+
+    async fn build_compressed(
+        sequence: impl IntoIterator<Item = Entry<String>>,
+        dir: PathBuf,
+        compression: Compression,
+    ) -> SSTable<String> {
+        let wb = WriteBuffer::create(dir.clone(), Sequence::default(), channel(16).0)
+            .await
+            .unwrap();
         for x in sequence {
-            wb.write(x).await
+            wb.write(x, Durability::Sync).await.unwrap()
         }
-        let b = wb.to_builder().await;
-        let table = b.build(&PathBuf::from("./")).await;
-        b.delete().await;
+        let b = wb.to_builder().await.unwrap();
+        let table = b.build(&dir, compression).await.unwrap();
+        b.delete().await.unwrap();
         table
     }
 
     #[tokio::test]
     async fn test_build() {
+        let dir = TempDir::new();
         let (k1, k2, k3) = (Key::new(), Key::new(), Key::new());
         let sequence = vec![
-            Entry::new(k1, EntryData::Data("okay1".to_string())),
-            Entry::new(k2, EntryData::Data("ok2".into())),
-            Entry::new(k1, EntryData::Deleted),
-            Entry::new(k3, EntryData::Data("okayyy3".into())),
+            Entry::new(k1.clone(), EntryData::Data("okay1".to_string())),
+            Entry::new(k2.clone(), EntryData::Data("ok2".into())),
+            Entry::new(k1.clone(), EntryData::Deleted),
+            Entry::new(k3.clone(), EntryData::Data("okayyy3".into())),
         ];
-        let t = build_sstable(sequence, PathBuf::from("./")).await;
+        let t = build_sstable(sequence, dir.join("")).await;
+        assert!(t.may_contain(&k1) && t.may_contain(&k2) && t.may_contain(&k3));
         let mut r = t.reader().await.unwrap();
-        assert_eq!(r.read(&k1).await, Some(EntryData::Deleted));
-        assert_eq!(r.read(&k2).await, Some(EntryData::Data("ok2".into())));
-        assert_eq!(r.read(&k3).await, Some(EntryData::Data("okayyy3".into())));
+        assert_eq!(r.read(&k1).await.unwrap(), Some(EntryData::Deleted));
+        assert_eq!(
+            r.read(&k2).await.unwrap(),
+            Some(EntryData::Data("ok2".into()))
+        );
+        assert_eq!(
+            r.read(&k3).await.unwrap(),
+            Some(EntryData::Data("okayyy3".into()))
+        );
         t.delete().await.unwrap();
     }
 
     #[tokio::test]
     async fn test_merge() {
+        let dir = TempDir::new();
         let (k1, k2, k3, k4, k5) = (Key::new(), Key::new(), Key::new(), Key::new(), Key::new());
         let sequence1 = vec![
-            Entry::new(k1, EntryData::Deleted),
-            Entry::new(k2, EntryData::Data("ok2".into())),
-            Entry::new(k3, EntryData::Data("okayyy3".into())),
-            Entry::new(k4, EntryData::Data("okayy4".into())),
+            Entry::new(k1.clone(), EntryData::Deleted),
+            Entry::new(k2.clone(), EntryData::Data("ok2".into())),
+            Entry::new(k3.clone(), EntryData::Data("okayyy3".into())),
+            Entry::new(k4.clone(), EntryData::Data("okayy4".into())),
         ];
         let sequence2 = vec![
-            Entry::new(k1, EntryData::Data("okay1".to_string())),
-            Entry::new(k2, EntryData::Data("okk2".into())),
-            Entry::new(k5, EntryData::Deleted),
+            Entry::new(k1.clone(), EntryData::Data("okay1".to_string())),
+            Entry::new(k2.clone(), EntryData::Data("okk2".into())),
+            Entry::new(k5.clone(), EntryData::Deleted),
         ];
         let sequence3: Vec<Entry<String>> = vec![
-            Entry::new(k1, EntryData::Deleted),
-            Entry::new(k2, EntryData::Data("ok2".into())),
-            Entry::new(k3, EntryData::Data("okayyy3".into())),
-            Entry::new(k4, EntryData::Data("okayy4".into())),
-            Entry::new(k5, EntryData::Deleted),
+            Entry::new(k1.clone(), EntryData::Deleted),
+            Entry::new(k2.clone(), EntryData::Data("ok2".into())),
+            Entry::new(k3.clone(), EntryData::Data("okayyy3".into())),
+            Entry::new(k4.clone(), EntryData::Data("okayy4".into())),
+            Entry::new(k5.clone(), EntryData::Deleted),
         ];
-        let t1 = build_sstable(sequence1, PathBuf::from("./")).await;
-        let t2 = build_sstable(sequence2, PathBuf::from("./")).await;
-        let t3 = SSTableBuilder::merge(&t1, &t2, &PathBuf::from("./")).await;
+        let t1 = build_sstable(sequence1, dir.join("")).await;
+        let t2 = build_sstable(sequence2, dir.join("")).await;
+        let t3 = SSTableBuilder::merge(&t1, &t2, &dir.join(""))
+            .await
+            .unwrap();
 
         let mut r = t3.reader().await.unwrap();
-        for Entry { key, data } in sequence3 {
-            assert_eq!(r.read(&key).await.unwrap(), data)
+        for Entry { key, data, .. } in sequence3.iter() {
+            assert_eq!(&r.read(key).await.unwrap().unwrap(), data)
         }
 
+        // Tombstones are dropped when nothing older can hold their keys.
+        let t4 = SSTableBuilder::compact(&[&t1, &t2], &dir.join(""), None, true, Compression::Lz4)
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        let mut r4 = t4.reader().await.unwrap();
+        assert_eq!(r4.len(), 3);
+        for Entry { key, data, .. } in sequence3 {
+            let live = match &data {
+                EntryData::Data(_) => Some(data.clone()),
+                _ => None,
+            };
+            assert_eq!(r4.read(&key).await.unwrap(), live);
+        }
+        drop((r, r4));
+
         t1.delete().await.unwrap();
         t2.delete().await.unwrap();
         t3.delete().await.unwrap();
+        t4.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let dir = TempDir::new();
+        let (k1, k2) = (Key::new(), Key::new());
+        let expired = EntryData::Expiring("new1".to_string(), 1);
+        let live = EntryData::expiring("new2".to_string(), Duration::from_secs(3600));
+        let newer = vec![
+            Entry::new(k1.clone(), expired),
+            Entry::new(k2.clone(), live.clone()),
+        ];
+        let older = vec![
+            Entry::new(k1.clone(), EntryData::Data("old1".to_string())),
+            Entry::new(k2.clone(), EntryData::Data("old2".into())),
+        ];
+        let t1 = build_sstable(newer, dir.join("")).await;
+        let t2 = build_sstable(older, dir.join("")).await;
+
+        // Expired entries keep hiding older values until they can be dropped.
+        let merged =
+            SSTableBuilder::compact(&[&t1, &t2], &dir.join(""), None, false, Compression::Lz4)
+                .await
+                .unwrap()
+                .pop()
+                .unwrap();
+        let mut r = merged.reader().await.unwrap();
+        assert_eq!(r.read(&k1).await.unwrap(), Some(EntryData::Deleted));
+        assert_eq!(r.read(&k2).await.unwrap(), Some(live.clone()));
+        drop(r);
+
+        let compacted =
+            SSTableBuilder::compact(&[&t1, &t2], &dir.join(""), None, true, Compression::None)
+                .await
+                .unwrap()
+                .pop()
+                .unwrap();
+        let mut r = compacted.reader().await.unwrap();
+        assert_eq!(r.len(), 1);
+        assert_eq!(r.read(&k1).await.unwrap(), None);
+        assert_eq!(r.read(&k2).await.unwrap(), Some(live));
+        drop(r);
+
+        for t in [t1, t2, merged, compacted] {
+            t.delete().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let dir = TempDir::new();
+        let keys: Vec<_> = (0..500)
+            .map(|i| Key::from(format!("key{:03}", i).as_str()))
+            .collect();
+        let entries = |keys: &[Key]| -> Vec<_> {
+            keys.iter()
+                .map(|k| {
+                    Entry::new(
+                        k.clone(),
+                        EntryData::Data(format!("{{\"value\": {:?}}}", k)),
+                    )
+                })
+                .collect()
+        };
+        let plain = build_sstable(entries(&keys), dir.join("")).await;
+        let compressed = build_compressed(entries(&keys), dir.join(""), Compression::Lz4).await;
+        assert_eq!(compressed.compression(), Compression::Lz4);
+        assert!(compressed.size() < plain.size());
+
+        let compressed = SSTable::<String>::new(&dir.join(""), compressed.id().to_string())
+            .await
+            .unwrap();
+        let mut r = compressed.reader().await.unwrap();
+        let mut expected = plain.reader().await.unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(r.read(k).await.unwrap(), expected.read(k).await.unwrap());
+            assert_eq!(r.read_index(i as u64).await.unwrap().unwrap().key, *k);
+        }
+        drop((r, expected));
+        plain.delete().await.unwrap();
+        compressed.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_variable_keys() {
+        let dir = TempDir::new();
+        let (k1, k2, k3) = (Key::from("apple"), Key::from("b"), Key::new());
+        let sequence = vec![
+            Entry::new(k1.clone(), EntryData::Data("okay1".to_string())),
+            Entry::new(k2.clone(), EntryData::Data("ok2".into())),
+            Entry::new(k3.clone(), EntryData::Data("okayyy3".into())),
+        ];
+        let t = build_sstable(sequence, dir.join("")).await;
+        assert_eq!(t.layout(), Layout::Block);
+        let mut r = t.reader().await.unwrap();
+        assert_eq!(r.read(&Key::from("a")).await.unwrap(), None);
+        assert_eq!(
+            r.read(&k1).await.unwrap(),
+            Some(EntryData::Data("okay1".into()))
+        );
+        assert_eq!(
+            r.read(&k2).await.unwrap(),
+            Some(EntryData::Data("ok2".into()))
+        );
+        assert_eq!(
+            r.read(&k3).await.unwrap(),
+            Some(EntryData::Data("okayyy3".into()))
+        );
+        assert_eq!(r.read_index(0).await.unwrap().unwrap().key, k3);
+        t.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fixed_layout() {
+        let dir = TempDir::new();
+        let (mut k1, mut k2) = (Key::new(), Key::new());
+        if k2 < k1 {
+            (k1, k2) = (k2, k1);
+        }
+        let (d1, d2) = (
+            bincode::serialize(&EntryData::Data("okay1".to_string())).unwrap(),
+            bincode::serialize(&EntryData::<String>::Deleted).unwrap(),
+        );
+        let offsets = [
+            k1.bytes(),
+            &0u64.to_be_bytes(),
+            &(d1.len() as u64).to_be_bytes(),
+            k2.bytes(),
+            &(d1.len() as u64).to_be_bytes(),
+            &(d2.len() as u64).to_be_bytes(),
+        ]
+        .concat();
+        let id = Key::new().hex();
+        let path = dir.join(&id);
+        ImmutableFile::create(path.with_extension("offsets"), &offsets)
+            .await
+            .unwrap();
+        ImmutableFile::create(path.with_extension("strings"), &[d1, d2].concat())
+            .await
+            .unwrap();
+
+        let t = SSTable::<String>::new(&dir.join(""), id).await.unwrap();
+        assert_eq!(t.layout(), Layout::Fixed);
+        assert_eq!(t.len(), 2);
+        let mut r = t.reader().await.unwrap();
+        assert_eq!(
+            r.read(&k1).await.unwrap(),
+            Some(EntryData::Data("okay1".into()))
+        );
+        assert_eq!(r.read(&k2).await.unwrap(), Some(EntryData::Deleted));
+        t.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_blocks() {
+        let dir = TempDir::new();
+        let mut keys: Vec<_> = (0..1000).map(|_| Key::new()).collect();
+        keys.sort();
+        let sequence = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| Entry::new(k.clone(), EntryData::Data(format!("value{}", i))));
+        let t = build_sstable(sequence, dir.join("")).await;
+        assert_eq!(t.len(), 1000);
+        let mut r = t.reader().await.unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(
+                r.read(k).await.unwrap(),
+                Some(EntryData::Data(format!("value{}", i)))
+            );
+            assert_eq!(r.read_index(i as u64).await.unwrap().unwrap().key, *k);
+            assert_eq!(r.lower_bound(Bound::Included(k)).await.unwrap(), i as u64);
+            assert_eq!(
+                r.lower_bound(Bound::Excluded(k)).await.unwrap(),
+                i as u64 + 1
+            );
+        }
+        assert_eq!(r.read(&Key::new()).await.unwrap(), None);
+        assert_eq!(r.read_index(1000).await.unwrap(), None);
+        t.delete().await.unwrap();
     }
 }
@@ -1,69 +1,163 @@
 use std::{
     marker::PhantomData,
+    ops::RangeBounds,
     path::{Path, PathBuf},
-    sync::Arc,
 };
 
-use dashmap::ReadOnlyView;
 use rocket::{
     serde::{DeserializeOwned, Serialize},
     tokio::fs::remove_file,
 };
 
 use crate::{
-    core::{entry::EntryData, key::Key},
-    persistance::files::AppendableFile,
+    core::{
+        entry::{Entry, EntryData},
+        key::Key,
+    },
+    persistance::{files::AppendableFile, wal},
+    Result,
 };
 
-use super::sstable::{OffsetEntry, SSTable};
+use super::{
+    block::{encode_entry, BlockHandle, Compression, Footer, TableIndex, BLOCK_SIZE, VERSION},
+    bloom::BloomFilter,
+    memtable::Memtable,
+    sstable::SSTable,
+};
 
 #[derive(Clone, Debug)]
 pub struct SSTableBuilder<T: Serialize> {
-    entries: Arc<ReadOnlyView<Key, EntryData<T>>>,
+    entries: Memtable<T>,
     dir: PathBuf,
     id: String,
+    /// The sequence of the last write before the buffer was created.
+    start: u64,
     entry_type: PhantomData<T>,
 }
 
-fn get_offset_bytes(entry: &OffsetEntry) -> Vec<u8> {
-    let offset_bytes = [
-        entry.key.bytes(),
-        &entry.offset.to_be_bytes()[..],
-        &entry.length.to_be_bytes()[..],
-    ]
-    .concat();
-    offset_bytes
+/// Writes a sorted sequence of entries to a new table in the `Block` layout.
+/// The file is only synced once it is complete.
+pub(super) struct TableWriter {
+    file: AppendableFile,
+    offset: u64,
+    entries: u64,
+    block: Vec<u8>,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    compression: Compression,
 }
 
-async fn copy_entry<T: Serialize>(
-    offsets: &mut AppendableFile,
-    strings: &mut AppendableFile,
-    offset: &mut u64,
-    key: Key,
-    data: &EntryData<T>,
-) {
-    let string_bytes = bincode::serialize(&data).unwrap();
-    let offset_bytes = get_offset_bytes(&OffsetEntry {
-        key,
-        offset: *offset,
-        length: string_bytes.len() as u64,
-    });
-    strings.append(&string_bytes).await.unwrap();
-    offsets.append(&offset_bytes).await.unwrap();
-    *offset += string_bytes.len() as u64;
+impl TableWriter {
+    async fn new(
+        dir: &Path,
+        id: String,
+        expected_keys: usize,
+        compression: Compression,
+    ) -> Result<TableWriter> {
+        TableWriter::create(
+            dir.join(id).with_extension("sst"),
+            expected_keys,
+            compression,
+        )
+        .await
+    }
+
+    pub(super) async fn create(
+        path: PathBuf,
+        expected_keys: usize,
+        compression: Compression,
+    ) -> Result<TableWriter> {
+        Ok(TableWriter {
+            file: AppendableFile::new(path).await?,
+            offset: 0,
+            entries: 0,
+            block: Vec::new(),
+            index: Vec::new(),
+            bloom: BloomFilter::new(expected_keys),
+            compression,
+        })
+    }
+
+    pub(super) async fn append<T: Serialize>(&mut self, entry: &Entry<T>) -> Result<()> {
+        if self.block.is_empty() {
+            self.index.push(BlockHandle {
+                first_key: entry.key.clone(),
+                offset: self.offset,
+                length: 0,
+                start: self.entries,
+            });
+        }
+        encode_entry(&mut self.block, entry)?;
+        self.bloom.insert(&entry.key);
+        self.entries += 1;
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush_block().await?;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Removes the partially written table.
+    pub(super) async fn discard(self) -> Result<()> {
+        Ok(self.file.delete().await?)
+    }
+
+    async fn flush_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = self.compression.compress(std::mem::take(&mut self.block));
+        self.file.write(&block).await?;
+        self.index.last_mut().unwrap().length = block.len() as u64;
+        self.offset += block.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the index, bloom filter and footer, then syncs and closes the
+    /// file, returning its path.
+    pub(super) async fn close(mut self) -> Result<PathBuf> {
+        self.flush_block().await?;
+        let index = bincode::serialize(&TableIndex {
+            compression: self.compression,
+            blocks: self.index,
+        })?;
+        let bloom = self.bloom.to_bytes();
+        let footer = Footer {
+            index_offset: self.offset,
+            index_length: index.len() as u64,
+            bloom_offset: self.offset + index.len() as u64,
+            bloom_length: bloom.len() as u64,
+            entries: self.entries,
+            version: VERSION,
+        };
+        self.file
+            .write(&[index, bloom, footer.to_bytes()].concat())
+            .await?;
+        Ok(self.file.close().await?)
+    }
+
+    async fn finish<T>(self) -> Result<SSTable<T>> {
+        let path = self.close().await?;
+        let id = path.file_stem().unwrap().to_string_lossy().to_string();
+        SSTable::new(path.parent().unwrap(), id).await
+    }
 }
 
-impl<T: Serialize + DeserializeOwned> SSTableBuilder<T> {
-    pub fn new(
-        entries: ReadOnlyView<Key, EntryData<T>>,
-        dir: PathBuf,
-        id: String,
-    ) -> SSTableBuilder<T> {
+impl<T: Serialize + DeserializeOwned + Clone> SSTableBuilder<T> {
+    pub fn new(entries: Memtable<T>, dir: PathBuf, id: String, start: u64) -> SSTableBuilder<T> {
         SSTableBuilder {
-            entries: Arc::new(entries),
+            entries,
             dir,
             id,
-            entry_type: PhantomData::default(),
+            start,
+            entry_type: PhantomData,
         }
     }
 
@@ -71,131 +165,164 @@ impl<T: Serialize + DeserializeOwned> SSTableBuilder<T> {
         &self.id
     }
 
+    /// Returns the sequence of the last write before the buffer was created.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
     fn path(&self) -> PathBuf {
         self.dir.join(&self.id).with_extension("wal")
     }
 
-    pub fn read(&self, key: &Key) -> Option<&EntryData<T>> {
-        self.entries.get(key)
+    pub fn read(&self, key: &Key) -> Option<EntryData<T>> {
+        self.entries.get(key, u64::MAX).map(|x| x.1)
     }
 
-    pub async fn build(&self, dir: &Path) -> SSTable<T> {
-        let mut entries: Vec<_> = self.entries.iter().collect();
-        entries.sort_by_key(|x| x.0);
-
-        let mut offsets = AppendableFile::new(dir.join(&self.id).with_extension("offsets"))
-            .await
-            .unwrap();
-        let mut strings = AppendableFile::new(dir.join(&self.id).with_extension("strings"))
-            .await
-            .unwrap();
+    pub fn range(&self, range: &impl RangeBounds<Key>) -> Vec<(Key, EntryData<T>)> {
+        self.entries.range(range, u64::MAX)
+    }
 
-        let mut offset = 0u64;
-        for (&key, v) in entries {
-            let string_bytes = bincode::serialize(v).unwrap();
-            let offset_bytes = get_offset_bytes(&OffsetEntry {
-                key,
-                offset,
-                length: string_bytes.len() as u64,
-            });
-            strings.append(&string_bytes).await.unwrap();
-            offsets.append(&offset_bytes).await.unwrap();
-            offset += string_bytes.len() as u64;
-        }
-        SSTable::new(dir, self.id.to_string()).await
+    pub fn memtable(&self) -> Memtable<T> {
+        self.entries.clone()
     }
 
-    pub async fn merge(young: &SSTable<T>, old: &SSTable<T>, dir: &Path) -> SSTable<T> {
-        let id = Key::new().hex();
+    /// Writes the newest version of each key to a new table.
+    pub async fn build(&self, dir: &Path, compression: Compression) -> Result<SSTable<T>> {
+        let entries = self.entries.latest(u64::MAX);
 
-        let mut offsets = AppendableFile::new(dir.join(&id).with_extension("offsets"))
-            .await
-            .unwrap();
-        let mut strings = AppendableFile::new(dir.join(&id).with_extension("strings"))
-            .await
-            .unwrap();
+        let mut writer =
+            TableWriter::new(dir, self.id.to_string(), entries.len(), compression).await?;
+        for entry in entries.iter() {
+            writer.append(entry).await?;
+        }
+        writer.finish().await
+    }
 
-        let mut young = young.reader().await.unwrap();
-        let mut old = old.reader().await.unwrap();
+    /// Merges two tables into one, keeping the entries of `young` over those
+    /// of `old`.
+    pub async fn merge(young: &SSTable<T>, old: &SSTable<T>, dir: &Path) -> Result<SSTable<T>> {
+        let mut tables =
+            SSTableBuilder::compact(&[young, old], dir, None, false, Compression::None).await?;
+        Ok(tables.pop().unwrap())
+    }
 
-        let mut offset = 0u64;
-        let mut indexes = (0, 0);
+    /// Merges tables, ordered from newest to oldest, keeping the newest entry
+    /// for each key. If a target size is given the output is split into
+    /// tables of roughly that many bytes, otherwise a single (possibly empty)
+    /// table is written. Expired entries are replaced by tombstones.
+    /// Tombstones should only be dropped when no older table can hold the keys
+    /// they delete.
+    pub async fn compact(
+        tables: &[&SSTable<T>],
+        dir: &Path,
+        target_size: Option<u64>,
+        drop_tombstones: bool,
+        compression: Compression,
+    ) -> Result<Vec<SSTable<T>>> {
+        let expected_keys = match target_size {
+            Some(_) => tables.iter().map(|t| t.len()).max().unwrap_or(0) as usize,
+            None => tables.iter().map(|t| t.len()).sum::<u64>() as usize,
+        };
 
-        let mut entries = (
-            young.read_index(indexes.0).await,
-            old.read_index(indexes.1).await,
-        );
+        let mut readers = Vec::new();
+        for t in tables {
+            readers.push(t.reader().await?);
+        }
+        let mut indexes = vec![0; readers.len()];
+        let mut entries = Vec::new();
+        for r in readers.iter_mut() {
+            entries.push(r.read_index(0).await?);
+        }
 
+        let mut output = Vec::new();
+        let mut writer =
+            TableWriter::new(dir, Key::new().hex(), expected_keys, compression).await?;
         loop {
-            match entries {
-                (None, None) => break,
-                (Some((key, ref data)), None) => {
-                    copy_entry(&mut offsets, &mut strings, &mut offset, key, data).await;
-                    indexes.0 += 1;
-                    entries.0 = young.read_index(indexes.0).await;
-                }
-                (None, Some((key, ref data))) => {
-                    copy_entry(&mut offsets, &mut strings, &mut offset, key, data).await;
-                    indexes.1 += 1;
-                    entries.1 = old.read_index(indexes.1).await;
-                }
-                (Some((k0, ref d0)), Some((k1, ref d1))) => {
-                    if k0 <= k1 {
-                        copy_entry(&mut offsets, &mut strings, &mut offset, k0, d0).await;
-                        indexes.0 += 1;
-                        entries.0 = young.read_index(indexes.0).await;
-                        if k0 == k1 {
-                            indexes.1 += 1;
-                            entries.1 = old.read_index(indexes.1).await;
-                        }
-                    } else {
-                        copy_entry(&mut offsets, &mut strings, &mut offset, k1, d1).await;
-                        indexes.1 += 1;
-                        entries.1 = old.read_index(indexes.1).await;
-                    }
+            let newest = entries
+                .iter()
+                .enumerate()
+                .filter_map(|(i, e)| e.as_ref().map(|e| (i, &e.key)))
+                .min_by(|a, b| a.1.cmp(b.1))
+                .map(|x| x.0);
+            let newest = match newest {
+                Some(i) => i,
+                None => break,
+            };
+            let mut entry = entries[newest].take().unwrap();
+            // Expired entries must still hide older values of the key.
+            if entry.data.is_deleted() {
+                entry.data = EntryData::Deleted;
+            }
+            if !(drop_tombstones && entry.data.is_deleted()) {
+                writer.append(&entry).await?;
+            }
+            for i in newest..readers.len() {
+                if i == newest || matches!(&entries[i], Some(e) if e.key == entry.key) {
+                    indexes[i] += 1;
+                    entries[i] = readers[i].read_index(indexes[i]).await?;
                 }
             }
+            if matches!(target_size, Some(size) if writer.size() >= size) {
+                output.push(writer.finish().await?);
+                writer =
+                    TableWriter::new(dir, Key::new().hex(), expected_keys, compression).await?;
+            }
+        }
+        if target_size.is_none() || !writer.is_empty() {
+            output.push(writer.finish().await?);
+        } else {
+            writer.discard().await?;
         }
+        Ok(output)
+    }
 
-        SSTable::new(dir, id).await
+    pub async fn delete(self) -> Result<()> {
+        Ok(remove_file(&self.path()).await?)
     }
 
-    pub async fn delete(self) {
-        remove_file(&self.path()).await.unwrap();
+    /// Moves the WAL into the archive directory instead of deleting it.
+    pub async fn archive(self, dir: &Path) -> Result<()> {
+        Ok(wal::archive(&self.path(), dir).await?)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
 
-    use rocket::tokio;
+    use rocket::tokio::{self, sync::broadcast::channel};
 
     use crate::{
         core::{
             entry::{Entry, EntryData},
             key::Key,
         },
-        sstables::write_buffer::WriteBuffer,
+        sstables::{
+            memtable::Sequence,
+            write_buffer::{Durability, WriteBuffer},
+        },
+        testing::TempDir,
     };
 
     #[tokio::test]
     async fn test() {
-        let wb = WriteBuffer::create(PathBuf::from("./")).await;
+        let dir = TempDir::new();
+        let wb = WriteBuffer::create(dir.join(""), Sequence::default(), channel(16).0)
+            .await
+            .unwrap();
         let (k1, k2, k3) = (Key::new(), Key::new(), Key::new());
         let sequence = vec![
-            Entry::new(k1, EntryData::Data("okay1".to_string())),
-            Entry::new(k2, EntryData::Data("ok2".into())),
-            Entry::new(k1, EntryData::Deleted),
-            Entry::new(k3, EntryData::Data("okayyy3".into())),
+            Entry::new(k1.clone(), EntryData::Data("okay1".to_string())),
+            Entry::new(k2.clone(), EntryData::Data("ok2".into())),
+            Entry::new(k1.clone(), EntryData::Deleted),
+            Entry::new(k3.clone(), EntryData::Data("okayyy3".into())),
         ];
         for x in sequence {
-            wb.write(x).await
+            wb.write(x, Durability::Sync).await.unwrap()
         }
-        let b = wb.to_builder().await;
-        assert_eq!(b.read(&k1), Some(&EntryData::Deleted));
-        assert_eq!(b.read(&k2), Some(&EntryData::Data("ok2".into())));
-        assert_eq!(b.read(&k3), Some(&EntryData::Data("okayyy3".into())));
-        b.delete().await;
+        let b = wb.to_builder().await.unwrap();
+        assert_eq!(b.read(&k1), Some(EntryData::Deleted));
+        assert_eq!(b.read(&k2), Some(EntryData::Data("ok2".into())));
+        assert_eq!(b.read(&k3), Some(EntryData::Data("okayyy3".into())));
+        b.delete().await.unwrap();
     }
 }
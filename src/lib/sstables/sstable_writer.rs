@@ -0,0 +1,135 @@
+use std::{marker::PhantomData, path::PathBuf, time::Duration};
+
+use rocket::{serde::Serialize, tokio::fs::metadata};
+
+use crate::{
+    core::{
+        entry::{Entry, EntryData},
+        key::Key,
+    },
+    Error, Result,
+};
+
+use super::{block::Compression, sstable_builder::TableWriter};
+
+/// Writes a table in the format used by the engine, without a running tree.
+/// Large amounts of data can be written this way and then added to a tree
+/// with `LSMTreeClient::ingest_sstable`, rather than logging each write.
+///
+/// Keys must be written in strictly increasing order.
+pub struct SSTableWriter<T> {
+    writer: TableWriter,
+    last: Option<Key>,
+    entry_type: PhantomData<T>,
+}
+
+impl<T: Serialize> SSTableWriter<T> {
+    /// Starts a new table at the path, which must not already exist. The
+    /// bloom filter is sized for `expected_keys`.
+    pub async fn create(
+        path: PathBuf,
+        expected_keys: usize,
+        compression: Compression,
+    ) -> Result<SSTableWriter<T>> {
+        if metadata(&path).await.is_ok() {
+            return Err(Error::InvalidArgument(format!(
+                "{} already exists",
+                path.display()
+            )));
+        }
+        Ok(SSTableWriter {
+            writer: TableWriter::create(path, expected_keys, compression).await?,
+            last: None,
+            entry_type: PhantomData,
+        })
+    }
+
+    /// Sets the key to the value, or deletes it if the value is `None`.
+    pub async fn write(&mut self, key: Key, data: Option<T>) -> Result<()> {
+        let data = data.map(EntryData::Data).unwrap_or(EntryData::Deleted);
+        self.append(Entry::new(key, data)).await
+    }
+
+    /// Sets the key to a value that reads as absent once the duration has
+    /// passed since the table was written.
+    pub async fn write_with_ttl(&mut self, key: Key, value: T, ttl: Duration) -> Result<()> {
+        self.append(Entry::new(key, EntryData::expiring(value, ttl)))
+            .await
+    }
+
+    async fn append(&mut self, entry: Entry<T>) -> Result<()> {
+        if matches!(&self.last, Some(last) if last >= &entry.key) {
+            return Err(Error::InvalidArgument(format!(
+                "Key {} was not written in increasing order",
+                entry.key.hex()
+            )));
+        }
+        self.writer.append(&entry).await?;
+        self.last = Some(entry.key);
+        Ok(())
+    }
+
+    /// Completes the table and syncs it to disk, returning its path.
+    pub async fn finish(self) -> Result<PathBuf> {
+        self.writer.close().await
+    }
+
+    /// Removes the partially written table.
+    pub async fn discard(self) -> Result<()> {
+        self.writer.discard().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::tokio;
+
+    use crate::{
+        core::{entry::EntryData, key::Key},
+        sstables::{block::Compression, sstable::SSTable},
+        testing::TempDir,
+        Error,
+    };
+
+    use super::SSTableWriter;
+
+    #[tokio::test]
+    async fn test_writer() {
+        let dir = TempDir::new();
+        let id = Key::new().hex();
+        let path = dir.join(format!("{}.sst", id));
+        let mut writer = SSTableWriter::create(path.clone(), 3, Compression::Lz4)
+            .await
+            .unwrap();
+        let (k1, k2, k3) = (Key::from("a"), Key::from("b"), Key::from("c"));
+        writer
+            .write(k1.clone(), Some("1".to_string()))
+            .await
+            .unwrap();
+        writer.write(k2.clone(), None).await.unwrap();
+        assert!(matches!(
+            writer.write(k1.clone(), None).await,
+            Err(Error::InvalidArgument(_))
+        ));
+        writer
+            .write(k3.clone(), Some("3".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(writer.finish().await.unwrap(), path);
+        assert!(SSTableWriter::<String>::create(path, 0, Compression::None)
+            .await
+            .is_err());
+
+        let table = SSTable::<String>::new(&dir.join(""), id).await.unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.range(), Some((&k1, &k3)));
+        let mut reader = table.reader().await.unwrap();
+        assert_eq!(
+            reader.read(&k1).await.unwrap(),
+            Some(EntryData::Data("1".to_string()))
+        );
+        assert_eq!(reader.read(&k2).await.unwrap(), Some(EntryData::Deleted));
+        drop(reader);
+        table.delete().await.unwrap();
+    }
+}
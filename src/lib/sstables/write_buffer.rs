@@ -1,22 +1,115 @@
+use std::future::Future;
 use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
 
-use dashmap::DashMap;
-use rocket::serde::{DeserializeOwned, Serialize};
-use rocket::tokio;
+use parking_lot::Mutex;
+use rocket::serde::{Deserialize, DeserializeOwned, Serialize};
+use rocket::tokio::{
+    self,
+    fs::{self, File},
+    sync::{broadcast, oneshot, oneshot::error::TryRecvError},
+};
 
 use crate::core::entry::EntryData;
 use crate::core::{entry::Entry, key::Key};
-use crate::persistance::wal::WAL;
+use crate::persistance::wal::{Timed, WAL};
+use crate::{Error, Result};
 
+use super::memtable::{Memtable, Sequence};
 use super::sstable_builder::SSTableBuilder;
 
+/// An entry logged before the WAL format was versioned, each in a record of
+/// its own, without a sequence number.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct LegacyEntry<T> {
+    key: Key,
+    data: EntryData<T>,
+}
+
+/// Converts a legacy entry to a batch with the given sequence.
+fn upgrade<T>(entry: LegacyEntry<T>, sequence: u64) -> Vec<Entry<T>> {
+    vec![Entry {
+        key: entry.key,
+        data: entry.data,
+        sequence,
+    }]
+}
+
+/// How much care is taken to persist a write before it is acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// The write is logged and synced to disk.
+    #[default]
+    Sync,
+    /// The write is logged, but only synced in the background every
+    /// `sync_interval`. It survives the process exiting, but not the machine
+    /// crashing before the next sync.
+    Buffered,
+    /// The write is only held in memory, and is lost if the process exits
+    /// before the buffer has been written to a table. This includes a
+    /// graceful `shutdown`, which leaves buffers to be replayed from their
+    /// WALs rather than writing them to tables. Its sequence number may then
+    /// be given to a later write, so the write is not sent to subscribers or
+    /// followers.
+    None,
+}
+
+/// Receives every batch written to a tree once it has been committed, in
+/// sequence order.
+pub type Changes<T> = broadcast::Sender<Arc<Vec<Entry<T>>>>;
+
+/// A batch waiting to be logged, and the writer to notify once it is durable.
+type Pending<T> = (Vec<Entry<T>>, Durability, oneshot::Sender<Result<()>>);
+
+/// The batches taken from the queue by a group commit. If the commit is
+/// cancelled before they are logged, they are returned to the front of the
+/// queue, so that the writers waiting on them are not abandoned.
+struct Group<'a, T> {
+    pending: &'a Mutex<Vec<Pending<T>>>,
+    batches: Vec<(Vec<Entry<T>>, Durability)>,
+    senders: Vec<oneshot::Sender<Result<()>>>,
+}
+
+impl<T> Drop for Group<'_, T> {
+    fn drop(&mut self) {
+        if self.batches.is_empty() {
+            return;
+        }
+        let mut pending = self.pending.lock();
+        let later = std::mem::take(&mut *pending);
+        pending.extend(
+            self.batches
+                .drain(..)
+                .zip(self.senders.drain(..))
+                .map(|((entries, durability), sender)| (entries, durability, sender)),
+        );
+        pending.extend(later);
+    }
+}
+
 #[derive(Debug)]
 pub struct WriteBuffer<T: Serialize + DeserializeOwned> {
-    entries: DashMap<Key, EntryData<T>>,
+    entries: Memtable<T>,
+    sequence: Sequence,
+    /// The sequence of the last write before the buffer was created. Every
+    /// entry in the buffer is newer.
+    start: u64,
+    changes: Changes<T>,
     dir: PathBuf,
     id: String,
-    file: tokio::sync::Mutex<WAL<Entry<T>>>,
+    file: tokio::sync::Mutex<WAL<Vec<Entry<T>>>>,
+    /// Batches queued for the next group commit.
+    pending: Mutex<Vec<Pending<T>>>,
+    /// Approximate size of the entries in bytes.
+    bytes: AtomicUsize,
+    /// Set while the WAL holds buffered writes that have not been synced.
+    dirty: AtomicBool,
     entry_type: PhantomData<T>,
 }
 
@@ -25,103 +118,563 @@ impl<T: Serialize + DeserializeOwned + Clone> WriteBuffer<T> {
         &self.id
     }
 
-    pub async fn open(dir: PathBuf, id: String) -> WriteBuffer<T> {
-        let (wal, existing) = WAL::<Entry<T>>::open(dir.join(&id).with_extension("wal"))
-            .await
-            .unwrap();
-        WriteBuffer {
-            entries: existing.into_iter().map(|x| (x.key, x.data)).collect(),
+    /// Replays the WAL of an existing buffer, advancing the sequence past
+    /// the entries it holds. Entries of unversioned logs, which have no
+    /// sequence numbers, are given the next ones.
+    pub async fn open(
+        dir: PathBuf,
+        id: String,
+        sequence: Sequence,
+        changes: Changes<T>,
+    ) -> Result<WriteBuffer<T>> {
+        let upgrade =
+            |entry: LegacyEntry<T>| upgrade(entry, sequence.fetch_add(1, Ordering::SeqCst) + 1);
+        let (wal, existing) =
+            WAL::<Vec<Entry<T>>>::open_with(dir.join(&id).with_extension("wal"), upgrade).await?;
+        let mut bytes = 0;
+        let mut start = None;
+        let entries = Memtable::new();
+        for batch in existing {
+            bytes += bincode::serialized_size(&batch)? as usize;
+            for x in batch {
+                sequence.fetch_max(x.sequence, Ordering::SeqCst);
+                start.get_or_insert(x.sequence - 1);
+                entries.insert(x.key, x.sequence, x.data);
+            }
+        }
+        Ok(WriteBuffer {
+            entries,
+            start: start.unwrap_or_else(|| sequence.load(Ordering::SeqCst)),
+            sequence,
+            changes,
             dir,
             id,
             file: tokio::sync::Mutex::new(wal),
-            entry_type: PhantomData::default(),
-        }
+            pending: Mutex::new(Vec::new()),
+            bytes: AtomicUsize::new(bytes),
+            dirty: AtomicBool::new(false),
+            entry_type: PhantomData,
+        })
+    }
+
+    /// Reads the batches logged in a WAL without changing it, each with the
+    /// time it was logged if that is known, along with the length of any torn
+    /// record at its end. Entries of unversioned logs, which have no sequence
+    /// numbers, are given sequence 0.
+    pub async fn read_wal(path: PathBuf) -> Result<(Vec<Timed<Vec<Entry<T>>>>, u64)> {
+        Ok(WAL::read_with(path, |entry: LegacyEntry<T>| upgrade(entry, 0)).await?)
     }
 
-    pub async fn create(dir: PathBuf) -> WriteBuffer<T> {
+    pub async fn create(
+        dir: PathBuf,
+        sequence: Sequence,
+        changes: Changes<T>,
+    ) -> Result<WriteBuffer<T>> {
         let id = Key::new().hex();
-        let wal = WAL::<Entry<T>>::create(dir.join(&id).with_extension("wal"))
-            .await
-            .unwrap();
-        WriteBuffer {
-            entries: DashMap::new(),
+        let wal = WAL::<Vec<Entry<T>>>::create(dir.join(&id).with_extension("wal")).await?;
+        Ok(WriteBuffer {
+            entries: Memtable::new(),
+            start: sequence.load(Ordering::SeqCst),
+            sequence,
+            changes,
             dir,
             id,
             file: tokio::sync::Mutex::new(wal),
-            entry_type: PhantomData::default(),
-        }
+            pending: Mutex::new(Vec::new()),
+            bytes: AtomicUsize::new(0),
+            dirty: AtomicBool::new(false),
+            entry_type: PhantomData,
+        })
     }
 
-    pub async fn to_builder(self) -> SSTableBuilder<T> {
+    pub async fn to_builder(self) -> Result<SSTableBuilder<T>> {
         let x = self.file.into_inner();
-        x.close().await.unwrap();
-        SSTableBuilder::new(self.entries.into_read_only(), self.dir, self.id)
+        x.close().await?;
+        Ok(SSTableBuilder::new(
+            self.entries,
+            self.dir,
+            self.id,
+            self.start,
+        ))
     }
 
-    pub async fn write(&self, entry: Entry<T>) {
-        {
+    pub async fn write(&self, entry: Entry<T>, durability: Durability) -> Result<()> {
+        self.write_batch(vec![entry], durability).await
+    }
+
+    /// Logs the entries as a single WAL record, so that recovery sees either
+    /// all of them or none of them. The entries share the next sequence
+    /// number, which is only published once all of them are in memory.
+    ///
+    /// Concurrent writers are committed in groups: the batch is queued, and
+    /// whichever writer next holds the WAL lock logs every queued batch with
+    /// a single sync, then releases the others once they are durable. If
+    /// that writer is cancelled first, the batches are queued again for the
+    /// next one.
+    pub async fn write_batch(&self, entries: Vec<Entry<T>>, durability: Durability) -> Result<()> {
+        let (sender, mut receiver) = oneshot::channel();
+        self.pending.lock().push((entries, durability, sender));
+        loop {
             let mut lock = self.file.lock().await;
-            lock.write(&entry).await.unwrap();
-            self.entries.insert(entry.key, entry.data);
+            self.commit_pending(&mut lock).await;
+            drop(lock);
+            match receiver.try_recv() {
+                Ok(result) => return result,
+                // Taken by a writer that was cancelled, and queued again.
+                Err(TryRecvError::Empty) => continue,
+                Err(TryRecvError::Closed) => return Err(Error::Closed),
+            }
         }
     }
 
+    /// Logs every queued batch, and notifies their writers of the outcome.
+    async fn commit_pending(&self, wal: &mut WAL<Vec<Entry<T>>>) {
+        let mut group = Group {
+            pending: &self.pending,
+            batches: Vec::new(),
+            senders: Vec::new(),
+        };
+        for (entries, durability, sender) in std::mem::take(&mut *self.pending.lock()) {
+            group.batches.push((entries, durability));
+            group.senders.push(sender);
+        }
+        if group.batches.is_empty() {
+            return;
+        }
+        let result = self.append(wal, &mut group.batches).await;
+        group.batches.clear();
+        for sender in group.senders.drain(..) {
+            // The writer may have been cancelled, in which case nobody waits.
+            let _ = sender.send(result.clone());
+        }
+    }
+
+    /// Logs the entries only if `condition` resolves to true, returning
+    /// whether they were written. The condition is checked while holding the
+    /// WAL lock, so no other write to the buffer can come between the check
+    /// and the write.
+    pub async fn write_batch_if<F, Fut>(
+        &self,
+        entries: Vec<Entry<T>>,
+        durability: Durability,
+        condition: F,
+    ) -> Result<bool>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<bool>>,
+    {
+        let mut lock = self.file.lock().await;
+        // The condition must see any writes queued before it.
+        self.commit_pending(&mut lock).await;
+        if !condition().await? {
+            return Ok(false);
+        }
+        self.append(&mut lock, &mut vec![(entries, durability)])
+            .await?;
+        Ok(true)
+    }
+
+    /// Logs a batch copied from another tree, keeping the sequence number it
+    /// was given there. The batch must be newer than every write so far.
+    pub async fn write_replicated(
+        &self,
+        entries: Vec<Entry<T>>,
+        durability: Durability,
+    ) -> Result<()> {
+        let mut lock = self.file.lock().await;
+        self.commit_pending(&mut lock).await;
+        let sequence = match entries.first() {
+            Some(x) => x.sequence,
+            None => return Ok(()),
+        };
+        if sequence <= self.sequence.load(Ordering::SeqCst)
+            || entries.iter().any(|x| x.sequence != sequence)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "Replicated batch {} is out of order",
+                sequence
+            )));
+        }
+        self.log(&mut lock, &mut vec![(entries, durability)], sequence)
+            .await
+    }
+
+    /// Logs each batch as its own record, giving each the next sequence
+    /// number. The log is synced once if any batch requires it, and batches
+    /// that are only held in memory are not logged at all.
+    async fn append(
+        &self,
+        wal: &mut WAL<Vec<Entry<T>>>,
+        batches: &mut Vec<(Vec<Entry<T>>, Durability)>,
+    ) -> Result<()> {
+        let first = self.sequence.load(Ordering::SeqCst) + 1;
+        for (i, (batch, _)) in batches.iter_mut().enumerate() {
+            for entry in batch.iter_mut() {
+                entry.sequence = first + i as u64;
+            }
+        }
+        let last = first + batches.len() as u64 - 1;
+        self.log(wal, batches, last).await
+    }
+
+    /// Logs batches whose entries have been given sequence numbers, then
+    /// publishes them, advancing the sequence to `last`. The batches are only
+    /// taken once they have been logged, so if the write is cancelled they
+    /// are left for the caller to log again.
+    async fn log(
+        &self,
+        wal: &mut WAL<Vec<Entry<T>>>,
+        batches: &mut Vec<(Vec<Entry<T>>, Durability)>,
+        last: u64,
+    ) -> Result<()> {
+        let mut size = 0;
+        for (batch, _) in batches.iter() {
+            size += bincode::serialized_size(batch)? as usize;
+        }
+        let logged = batches.iter().filter(|x| x.1 != Durability::None);
+        if logged.clone().next().is_some() {
+            let sync = batches.iter().any(|x| x.1 == Durability::Sync);
+            wal.write_all(logged.map(|x| &x.0), sync).await?;
+            // Syncing also persists any buffered writes logged before.
+            self.dirty.store(!sync, Ordering::SeqCst);
+        }
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        let mut published = Vec::new();
+        for (batch, durability) in batches.drain(..) {
+            for entry in batch.iter() {
+                self.entries
+                    .insert(entry.key.clone(), entry.sequence, entry.data.clone());
+            }
+            match batch.first() {
+                // An unlogged write's sequence may be given out again after
+                // a restart, so nobody else may see it.
+                Some(x) if durability == Durability::None => {
+                    self.entries.insert_unlogged(x.sequence)
+                }
+                Some(_) => published.push(Arc::new(batch)),
+                None => {}
+            }
+        }
+        self.sequence.store(last, Ordering::SeqCst);
+        // Batches are only published once reads can see them. They are sent
+        // even if nobody seems to be listening, as a subscriber may have
+        // joined too late to replay them from the buffer.
+        for batch in published {
+            let _ = self.changes.send(batch);
+        }
+        Ok(())
+    }
+
+    /// Flushes the WAL to disk.
+    pub async fn sync(&self) -> Result<()> {
+        let mut lock = self.file.lock().await;
+        lock.sync().await?;
+        self.dirty.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Copies the WAL to the path and syncs the copy, returning the sequence
+    /// of the last write it holds. Writes are held back meanwhile, so the copy
+    /// never ends part way through a batch.
+    pub async fn copy_wal(&self, path: &Path) -> Result<u64> {
+        let lock = self.file.lock().await;
+        let sequence = self.sequence.load(Ordering::SeqCst);
+        fs::copy(lock.path(), path).await?;
+        File::open(path).await?.sync_all().await?;
+        Ok(sequence)
+    }
+
+    /// Returns whether the WAL holds buffered writes that have not been
+    /// synced.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
     pub fn size(&self) -> usize {
         self.entries.len()
     }
 
+    pub fn size_bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
     pub fn read(&self, key: &Key) -> Option<EntryData<T>> {
-        self.entries.get(key).map(|x| x.clone())
+        self.entries.get(key, u64::MAX).map(|x| x.1)
     }
 
-    pub async fn from(dir: PathBuf, id: String) -> WriteBuffer<T> {
-        let (wal, existing) = WAL::<Entry<T>>::open(dir.join(&id).with_extension("wal"))
-            .await
-            .unwrap();
-        WriteBuffer {
-            entries: existing.into_iter().map(|x| (x.key, x.data)).collect(),
-            dir,
-            id,
-            file: tokio::sync::Mutex::new(wal),
-            entry_type: PhantomData::default(),
-        }
+    pub fn range(&self, range: &impl RangeBounds<Key>) -> Vec<(Key, EntryData<T>)> {
+        self.entries.range(range, u64::MAX)
+    }
+
+    /// Returns the sequence of the last write before the buffer was created.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Returns the entries of the buffer, including any written later.
+    pub fn memtable(&self) -> Memtable<T> {
+        self.entries.clone()
     }
 
-    pub async fn close(self) -> PathBuf {
-        self.file.into_inner().close().await.unwrap()
+    pub async fn from(
+        dir: PathBuf,
+        id: String,
+        sequence: Sequence,
+        changes: Changes<T>,
+    ) -> Result<WriteBuffer<T>> {
+        WriteBuffer::open(dir, id, sequence, changes).await
+    }
+
+    pub async fn close(self) -> Result<PathBuf> {
+        Ok(self.file.into_inner().close().await?)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
-
-    use rocket::tokio::{self, fs::remove_file};
+    use rocket::tokio::{self, fs::OpenOptions, sync::broadcast::channel};
 
     use crate::{
         core::{
             entry::{Entry, EntryData},
             key::Key,
         },
-        sstables::write_buffer::WriteBuffer,
+        sstables::{
+            memtable::Sequence,
+            write_buffer::{Durability, WriteBuffer},
+        },
+        testing::TempDir,
     };
 
+    use std::{
+        future::{poll_fn, Future},
+        pin::Pin,
+        sync::{atomic::Ordering, Arc},
+        task::Poll,
+    };
+
+    /// Polls the future once, returning whether it is still pending.
+    async fn poll_once(future: &mut Pin<Box<impl Future>>) -> bool {
+        poll_fn(|cx| Poll::Ready(future.as_mut().poll(cx).is_pending())).await
+    }
+
     #[tokio::test]
     async fn test() {
-        let wb = WriteBuffer::create(PathBuf::from("./")).await;
+        let dir = TempDir::new();
+        let wb = WriteBuffer::create(dir.join(""), Sequence::default(), channel(16).0)
+            .await
+            .unwrap();
         let (k1, k2, k3) = (Key::new(), Key::new(), Key::new());
         let sequence = vec![
-            Entry::new(k1, EntryData::Data("okay1".to_string())),
-            Entry::new(k2, EntryData::Data("ok2".into())),
-            Entry::new(k1, EntryData::Deleted),
-            Entry::new(k3, EntryData::Data("okayyy3".into())),
+            Entry::new(k1.clone(), EntryData::Data("okay1".to_string())),
+            Entry::new(k2.clone(), EntryData::Data("ok2".into())),
+            Entry::new(k1.clone(), EntryData::Deleted),
+            Entry::new(k3.clone(), EntryData::Data("okayyy3".into())),
         ];
         for x in sequence {
-            wb.write(x).await
+            wb.write(x, Durability::Sync).await.unwrap()
         }
         assert_eq!(wb.read(&k1), Some(EntryData::Deleted));
         assert_eq!(wb.read(&k2), Some(EntryData::Data("ok2".into())));
         assert_eq!(wb.read(&k3), Some(EntryData::Data("okayyy3".into())));
-        remove_file(wb.close().await).await.unwrap();
+        wb.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let dir = TempDir::new();
+        let wb = WriteBuffer::create(dir.join(""), Sequence::default(), channel(16).0)
+            .await
+            .unwrap();
+        let (k1, k2, k3) = (Key::new(), Key::new(), Key::new());
+        wb.write_batch(
+            vec![
+                Entry::new(k1.clone(), EntryData::Data("okay1".to_string())),
+                Entry::new(k2.clone(), EntryData::Deleted),
+            ],
+            Durability::Sync,
+        )
+        .await
+        .unwrap();
+        let id = wb.id().to_string();
+        let path = wb.close().await.unwrap();
+
+        let wb = WriteBuffer::<String>::open(
+            dir.join(""),
+            id.clone(),
+            Sequence::default(),
+            channel(16).0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(wb.read(&k1), Some(EntryData::Data("okay1".into())));
+        assert_eq!(wb.read(&k2), Some(EntryData::Deleted));
+        wb.write_batch(
+            vec![
+                Entry::new(k1.clone(), EntryData::Deleted),
+                Entry::new(k3.clone(), EntryData::Data("okayyy3".into())),
+            ],
+            Durability::Sync,
+        )
+        .await
+        .unwrap();
+        wb.close().await.unwrap();
+
+        // Simulate a crash part way through writing the second batch.
+        let file = OpenOptions::new().write(true).open(&path).await.unwrap();
+        let size = file.metadata().await.unwrap().len();
+        file.set_len(size - 1).await.unwrap();
+        drop(file);
+
+        let wb = WriteBuffer::<String>::open(dir.join(""), id, Sequence::default(), channel(16).0)
+            .await
+            .unwrap();
+        assert_eq!(wb.read(&k1), Some(EntryData::Data("okay1".into())));
+        assert_eq!(wb.read(&k3), None);
+        wb.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unversioned() {
+        // Logs written before the format was versioned hold one entry per
+        // record, framed by its length.
+        let (k1, k2) = (Key::new(), Key::new());
+        let entries = vec![
+            (k1.clone(), EntryData::Data("okay1".to_string())),
+            (k2.clone(), EntryData::Data("ok2".to_string())),
+            (k1.clone(), EntryData::Deleted),
+        ];
+        let mut bytes = Vec::new();
+        for entry in entries {
+            let payload = bincode::serialize(&entry).unwrap();
+            bytes.extend(payload.len().to_be_bytes());
+            bytes.extend(payload);
+        }
+        let dir = TempDir::new();
+        let id = Key::new().hex();
+        tokio::fs::write(dir.join(format!("{}.wal", id)), bytes)
+            .await
+            .unwrap();
+
+        let sequence = Sequence::default();
+        let wb =
+            WriteBuffer::<String>::open(dir.join(""), id.clone(), sequence.clone(), channel(16).0)
+                .await
+                .unwrap();
+        assert_eq!(sequence.load(Ordering::SeqCst), 3);
+        assert_eq!(wb.read(&k1), Some(EntryData::Deleted));
+        assert_eq!(wb.read(&k2), Some(EntryData::Data("ok2".into())));
+        wb.close().await.unwrap();
+
+        let wb = WriteBuffer::<String>::open(dir.join(""), id, Sequence::default(), channel(16).0)
+            .await
+            .unwrap();
+        let memtable = wb.memtable();
+        assert_eq!(
+            memtable.get(&k1, 2),
+            Some((1, EntryData::Data("okay1".into())))
+        );
+        assert_eq!(memtable.get(&k1, u64::MAX), Some((3, EntryData::Deleted)));
+        wb.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_group_commit() {
+        let dir = TempDir::new();
+        let sequence = Sequence::default();
+        let wb = Arc::new(
+            WriteBuffer::create(dir.join(""), sequence.clone(), channel(16).0)
+                .await
+                .unwrap(),
+        );
+        let keys: Vec<_> = (0..50).map(|_| Key::new()).collect();
+        let tasks: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| {
+                let (wb, k) = (wb.clone(), k.clone());
+                tokio::spawn(async move {
+                    wb.write(
+                        Entry::new(k, EntryData::Data(format!("value{}", i))),
+                        Durability::Sync,
+                    )
+                    .await
+                    .unwrap()
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await.unwrap();
+        }
+        assert_eq!(sequence.load(Ordering::SeqCst), 50);
+        let id = wb.id().to_string();
+        Arc::try_unwrap(wb).unwrap().close().await.unwrap();
+
+        let wb = WriteBuffer::<String>::open(dir.join(""), id, Sequence::default(), channel(16).0)
+            .await
+            .unwrap();
+        let mut sequences: Vec<_> = keys
+            .iter()
+            .map(|k| wb.memtable().get(k, u64::MAX).unwrap().0)
+            .collect();
+        sequences.sort();
+        assert_eq!(sequences, (1..=50).collect::<Vec<_>>());
+        wb.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_commit() {
+        let dir = TempDir::new();
+        let sequence = Sequence::default();
+        let wb = Arc::new(
+            WriteBuffer::create(dir.join(""), sequence.clone(), channel(16).0)
+                .await
+                .unwrap(),
+        );
+        let (k1, k2) = (Key::new(), Key::new());
+
+        // Queue two writers behind the WAL lock, so that the first leads a
+        // group commit of both batches.
+        let lock = wb.file.lock().await;
+        let mut leader = Box::pin(wb.write(
+            Entry::new(k1.clone(), EntryData::Data("okay1".to_string())),
+            Durability::Sync,
+        ));
+        assert!(poll_once(&mut leader).await);
+        let follower = {
+            let wb = wb.clone();
+            let k2 = k2.clone();
+            tokio::spawn(async move {
+                wb.write(
+                    Entry::new(k2, EntryData::Data("ok2".into())),
+                    Durability::Sync,
+                )
+                .await
+            })
+        };
+        tokio::task::yield_now().await;
+        drop(lock);
+
+        // Cancel the leader part way through logging the group.
+        poll_once(&mut leader).await;
+        drop(leader);
+        follower.await.unwrap().unwrap();
+        assert_eq!(wb.read(&k2), Some(EntryData::Data("ok2".into())));
+        let id = wb.id().to_string();
+        Arc::try_unwrap(wb).unwrap().close().await.unwrap();
+
+        // Both batches are logged once, whether or not the leader got as
+        // far as logging them.
+        let path = dir.join(format!("{}.wal", id));
+        let (batches, _) = WriteBuffer::<String>::read_wal(path).await.unwrap();
+        let sequences: Vec<_> = batches.iter().map(|b| b.0[0].sequence).collect();
+        assert_eq!(sequences, vec![1, 2]);
+        assert_eq!(sequence.load(Ordering::SeqCst), 2);
+        let wb = WriteBuffer::<String>::open(dir.join(""), id, Sequence::default(), channel(16).0)
+            .await
+            .unwrap();
+        assert_eq!(wb.read(&k1), Some(EntryData::Data("okay1".into())));
+        assert_eq!(wb.read(&k2), Some(EntryData::Data("ok2".into())));
+        wb.close().await.unwrap();
     }
 }
@@ -0,0 +1,39 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{core::key::Key, lsm_trees::options::LSMTreeOptions};
+
+/// Options that make trees flush and merge after a few small writes.
+pub(crate) fn options() -> LSMTreeOptions {
+    LSMTreeOptions {
+        memtable_size: 256,
+        max_queued_builders: 2,
+        poll_interval: Duration::from_millis(10),
+        ..LSMTreeOptions::default()
+    }
+}
+
+/// A directory for the files of a test, removed when dropped so that a
+/// failing test does not leave them behind.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> TempDir {
+        let path = env::temp_dir().join(format!("locker-{}", Key::new().hex()));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}